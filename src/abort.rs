@@ -0,0 +1,66 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// The main thread's end of the abort handshake. Once the synth thread has finished an utterance
+/// that `TTS_SHOULD_ABORT` was set for, it clears the flag and sends the utterance's ID here.
+///
+/// If that doesn't happen within `timeout`, the engine is stuck somewhere it doesn't call back
+/// from, and waiting longer won't help. The flag is then left armed, so the utterance is cut off
+/// as soon as the engine gets back to the callback, and anything queued in the meantime plays
+/// after that. The caller carries on as if the abort had gone through.
+pub struct AbortHandshake {
+    acks: Receiver<u32>,
+    timeout: Duration,
+    /// aborts confirmed in time
+    pub completed: u32,
+    /// aborts that ran into the timeout
+    pub timeouts: u32,
+    /// how long the last confirmed abort took
+    pub last_latency: Duration,
+    /// how long the slowest confirmed abort took
+    pub worst_latency: Duration,
+}
+
+impl AbortHandshake {
+    pub fn new(acks: Receiver<u32>, timeout: Duration) -> AbortHandshake {
+        AbortHandshake {
+            acks,
+            timeout,
+            completed: 0,
+            timeouts: 0,
+            last_latency: Duration::ZERO,
+            worst_latency: Duration::ZERO,
+        }
+    }
+
+    /// Waits for the synth thread to confirm that utterance `id` has been aborted, `requested`
+    /// being when the abort was asked for. Returns false if it timed out.
+    pub fn wait(&mut self, id: u32, requested: Instant) -> bool {
+        let deadline = requested + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.acks.recv_timeout(remaining) {
+                Ok(acked) if acked == id => {
+                    self.completed += 1;
+                    self.last_latency = requested.elapsed();
+                    self.worst_latency = self.worst_latency.max(self.last_latency);
+                    log::info!("abort of #{} took {}ms (worst so far {}ms)",
+                        id, self.last_latency.as_millis(), self.worst_latency.as_millis());
+                    return true;
+                }
+                // a late confirmation for an abort that already timed out
+                Ok(acked) => log::debug!("stale abort confirmation for #{}", acked),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.timeouts += 1;
+                    log::warn!("timeout waiting for #{} to abort after {}ms ({} so far), leaving the abort armed",
+                        id, self.timeout.as_millis(), self.timeouts);
+                    return false;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    log::error!("synth thread has gone away");
+                    return false;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,497 @@
+//! Extensions to the `xous-tts-backend` protocol that are specific to this espeak-ng server.
+//!
+//! The base opcodes keep the discriminants assigned by `TtsBeOpcode`, so clients that only
+//! know about the upstream crate keep working. Everything added here starts at
+//! `EXT_OPCODE_BASE` so that it can't collide with future upstream opcodes.
+//!
+//! Every client process gets a session of its own, keyed by its PID: its callback, voice and
+//! prosody are kept apart from everyone else's, and its audio only goes to its own callback.
+//!
+//! The layout of the messages below is versioned by `PROTOCOL_REVISION`. A client has to send
+//! `Negotiate` before `RegisterCbExt`, so that a server it doesn't match turns it away with a
+//! reason instead of misreading its messages. Clients that register with the plain `RegisterCb`
+//! have the upstream layout and don't need to; the extension messages they send afterwards aren't
+//! checked against a revision, so they're on their own if they use them.
+//!
+//! A request that can't be served as it was sent is logged and refused: a mutable lend comes back
+//! with no valid data, so decoding the reply fails, and a blocking scalar gets a reply of 0. That
+//! covers undecodable requests, scalars sent to an opcode that expects memory and unknown opcodes.
+//! The server carries on either way.
+
+use xous_tts_backend::TtsBeOpcode;
+
+pub const EXT_OPCODE_BASE: isize = 0x100;
+
+/// Bumped whenever the layout or meaning of an extension message changes.
+pub const PROTOCOL_REVISION: u32 = 1;
+/// The oldest revision this server still understands.
+pub const MIN_PROTOCOL_REVISION: u32 = 1;
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+pub enum Opcode {
+    /// convert a string to a wave file; see `TtsBeOpcode::StrToWav`
+    StrToWav = TtsBeOpcode::StrToWav as isize,
+    /// register the callback for wave data; see `TtsBeOpcode::RegisterCb`
+    RegisterCb = TtsBeOpcode::RegisterCb as isize,
+    /// Blocking scalar. Exit the server; see `TtsBeOpcode::Quit`. Speech is stopped as with
+    /// `Stop`, the engine is shut down and every registered client gets a `TtsBeNotice::Quit`,
+    /// or an `Abort` on its audio stream if it didn't register for notices, before its
+    /// connection is closed. The reply comes once all of that is done; if the engine is hung,
+    /// the server gives up on shutting it down after the watchdog timeout and exits anyway.
+    Quit = TtsBeOpcode::Quit as isize,
+
+    /// Scalar. `arg1` is the opcode on the already-registered callback connection that
+    /// receives `TtsBeNotice` messages. Must be sent after `RegisterCb`.
+    RegisterNoticeCb = EXT_OPCODE_BASE,
+    /// Memory message, lent mutably: a `TtsPhonemes` with `text` and `format` set. The server
+    /// fills in `phonemes` (or `error`) without producing any audio. This is serviced by the
+    /// synthesis thread, so it completes after any utterance that is already in progress.
+    TextToPhonemes,
+    /// Memory message, lent mutably: a `TtsVoiceList` with `start` set. The server fills in up to
+    /// `VOICE_PAGE_LEN` voices from that index, along with the total, so clients page through
+    /// the list until `start + voices.len() >= total`.
+    ListVoices,
+    /// Memory message, lent mutably: a `TtsSetVoice`. The server checks the selection against
+    /// the engine and fills in `error` if no voice matches; otherwise the voice is used for
+    /// every utterance from then on, until it is changed again.
+    SetVoice,
+    /// Memory message, lent mutably: a `TtsBackendConfigExt`. Same as `RegisterCb`, plus the
+    /// notice opcode and the full set of prosody parameters. If any parameter is out of range
+    /// the server fills in `rejected` and leaves the existing registration untouched. Only
+    /// accepted after a successful `Negotiate`; before that it's refused.
+    RegisterCbExt,
+    /// Memory message, lent mutably: a `TtsSetParams`. This doesn't touch the callback
+    /// registration. The prosody changes are range checked and answered right away; if one
+    /// is out of range the server fills in `rejected` and nothing changes. Otherwise the new
+    /// settings take effect at the next sentence of the utterance that is playing, or with the
+    /// next utterance if nothing is. A voice given here can only be checked once it is applied,
+    /// so if it doesn't exist the client finds out through a `TtsBeNotice::Error`.
+    SetParams,
+    /// Memory message, lent mutably: a `TtsStrToWav`. Same as `StrToWav`, but the overrides
+    /// apply to this utterance only and the client's own settings are left alone, and `mode`
+    /// and `priority` decide what happens to speech that is already playing or queued. The server answers as
+    /// soon as the utterance is queued; if an override is out of range it fills in `rejected`,
+    /// and if the utterance was turned away by `DropIfBusy` it sets `dropped`.
+    StrToWavExt,
+    /// Memory message, lent mutably: a `TtsStrToWav`. Same as `StrToWavExt`, but the server only
+    /// answers once the utterance has been spoken to the end, aborted or given up on, with
+    /// `outcome` filled in. The audio goes to the callback as usual.
+    StrToWavSync,
+    /// Scalar. Stops the utterance that is playing at the next word and holds it, along with
+    /// everything queued at or below its priority. Audio that has already been delivered isn't
+    /// recalled. Anything that outranks the paused speech is still spoken in the meantime. Like
+    /// `Stop`, it only reaches the caller's own speech unless the server mixes preemptively, so
+    /// other sessions keep talking.
+    Pause,
+    /// Scalar. Carries on from the word where the caller's `Pause` stopped.
+    Resume,
+    /// Blocking scalar. Aborts the utterance that is playing and drops everything queued or
+    /// paused, whatever its priority. The reply comes once speech has actually stopped: `arg1`
+    /// is the ID of the utterance that was cut off and `arg2` how many characters of its text had
+    /// been spoken; if nothing was playing, that is the first paused or preempted utterance that
+    /// was dropped, and both are 0 if there was none. The audio stream gets an `Abort` and the
+    /// notice callback a `TtsBeNotice::Aborted` for the utterance that was playing. A paused or
+    /// preempted one only gets the notice, as its audio has already stopped.
+    Stop,
+    /// Blocking scalar. Replies with `arg1` a `TtsState`, `arg2` the ID of the current utterance
+    /// (the paused one when paused, 0 when idle), `arg3` how many characters of its text have been
+    /// spoken, `arg4` the number of utterances waiting in the queue, paused ones included, and
+    /// `arg5` how long the last abort took to be confirmed, in ms.
+    Status,
+    /// Scalar. Ends the sender's session: its speech is stopped, its settings are forgotten and
+    /// the callback connection is closed. The same happens on its own if a callback can't be
+    /// delivered because the client has gone away.
+    UnregisterCb,
+    /// Blocking scalar. `arg1` to `arg4` carry a token that, when the server only admits clients
+    /// holding one, lets the sender register a callback and have text spoken for as long as its
+    /// session lasts. Replies with `arg1` 1 if the sender may now do so, 0 if not. Requests
+    /// from a client that isn't allowed are turned away: a plain `RegisterCb` or `StrToWav` is
+    /// dropped, `StrToWavExt`, `StrToWavSync` and `RegisterCbExt` come back with `denied` set,
+    /// and every other request except `Negotiate` and `UnregisterCb` is refused.
+    Authorize,
+    /// Blocking scalar. `arg1` is the `PROTOCOL_REVISION` the client was built against and `arg2`
+    /// the `TtsCapability` bits it can't do without. Replies with `arg1` a `TtsNegotiation`, `arg2`
+    /// this server's `PROTOCOL_REVISION`, `arg3` its `MIN_PROTOCOL_REVISION` and `arg4` the
+    /// capability bits it supports. `RegisterCbExt` is refused until the sender has negotiated
+    /// successfully. After a failed negotiation every other extension memory message is refused
+    /// too, and a plain `RegisterCb` is ignored, until the sender negotiates successfully.
+    Negotiate,
+}
+
+/// The speech parameters that can be tuned through espeak's `espeak_ng_SetParameter`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum TtsParam {
+    /// words per minute
+    Rate,
+    /// base pitch, 50 is normal
+    Pitch,
+    /// pitch range, 0 is monotone and 50 is normal
+    Range,
+    /// amplitude, 100 is normal; values above that may clip
+    Volume,
+    /// extra pause between words, in units of 10ms at the default rate
+    WordGap,
+    /// intonation emphasis, from 0 (none) to 5 (every word strongly emphasized)
+    Emphasis,
+}
+
+impl TtsParam {
+    /// The values espeak-ng accepts for this parameter.
+    pub fn legal_range(&self) -> core::ops::RangeInclusive<u32> {
+        match self {
+            TtsParam::Rate => 80..=450,
+            TtsParam::Pitch => 0..=100,
+            TtsParam::Range => 0..=100,
+            TtsParam::Volume => 0..=200,
+            // espeak doesn't bound this, but a gap of more than a second is certainly a mistake
+            TtsParam::WordGap => 0..=100,
+            TtsParam::Emphasis => 0..=5,
+        }
+    }
+}
+
+/// A set of prosody changes; `None` leaves the parameter as it is.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsProsody {
+    pub words_per_minute: Option<u32>,
+    pub pitch: Option<u32>,
+    pub range: Option<u32>,
+    pub volume: Option<u32>,
+    pub word_gap: Option<u32>,
+    pub emphasis: Option<u32>,
+}
+
+#[derive(Debug, Copy, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsBackendConfigExt {
+    pub sid: [u32; 4],
+    pub op: u32,
+    pub samples_per_cb: Option<u32>,
+    /// opcode for `TtsBeNotice` messages, see `RegisterNoticeCb`
+    pub notice_op: Option<u32>,
+    pub prosody: TtsProsody,
+    /// `None` uses the server's configured preset
+    pub latency: Option<TtsLatency>,
+    /// the first parameter that was out of its legal range; filled in by the server
+    pub rejected: Option<TtsParam>,
+    /// set if the client isn't allowed to register, see `Opcode::Authorize`; filled in by the server
+    pub denied: bool,
+}
+
+/// How espeak's output buffer is sized, which decides how much audio each callback carries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum TtsLatency {
+    /// small buffers, so the first audio arrives as early as possible; for key echo and the like
+    LowLatency,
+    /// espeak's own default
+    Balanced,
+    /// the largest buffers a `TtsBackendData` can carry, for the fewest callbacks; for long reads
+    Throughput,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum TtsGender {
+    Unspecified,
+    Male,
+    Female,
+}
+
+#[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsVoiceLanguage {
+    /// lower numbers are preferred when matching a voice by language
+    pub priority: u8,
+    /// language tag, with optional dialect qualifier, e.g. "en-gb"
+    pub language: String,
+}
+
+#[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsVoice {
+    pub name: String,
+    pub languages: Vec<TtsVoiceLanguage>,
+    pub gender: TtsGender,
+    /// age in years, 0 if unspecified
+    pub age: u8,
+    /// the voice's file name within espeak-ng-data/voices
+    pub identifier: String,
+}
+
+/// Keeps a page of voices comfortably inside a single 4k IPC buffer.
+pub const VOICE_PAGE_LEN: usize = 16;
+
+#[derive(Debug, Clone, Default, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsVoiceList {
+    /// index of the first voice to return; set by the client
+    pub start: u32,
+    /// number of voices available in this build; filled in by the server
+    pub total: u32,
+    /// the requested page of voices; filled in by the server
+    pub voices: Vec<TtsVoice>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum PhonemeFormat {
+    /// International Phonetic Alphabet, as UTF-8
+    Ipa,
+    /// espeak's ASCII phoneme mnemonics, as used in its dictionaries
+    Mnemonics,
+}
+
+#[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsPhonemes {
+    pub text: String,
+    pub format: PhonemeFormat,
+    /// the transcription, one clause after another separated by spaces; filled in by the server
+    pub phonemes: String,
+    /// the `espeak_ng_STATUS` code if the transcription failed; filled in by the server. E2BIG
+    /// means it didn't fit in the memory the client lent, and `text` comes back empty too.
+    pub error: Option<u32>,
+}
+
+/// The answer to `Negotiate`.
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TtsNegotiation {
+    Accepted,
+    /// the client's revision is older than `MIN_PROTOCOL_REVISION`
+    ClientTooOld,
+    /// the client's revision is newer than `PROTOCOL_REVISION`
+    ClientTooNew,
+    /// the revision is fine, but the server lacks a capability the client requires
+    MissingCapability,
+}
+
+impl TtsNegotiation {
+    /// The answer to a client built against `revision` that can't do without the capability bits
+    /// in `required`, from a server with the capability bits in `supported`.
+    pub fn decide(revision: u32, required: u32, supported: u32) -> TtsNegotiation {
+        if revision < MIN_PROTOCOL_REVISION {
+            TtsNegotiation::ClientTooOld
+        } else if revision > PROTOCOL_REVISION {
+            TtsNegotiation::ClientTooNew
+        } else if required & !supported != 0 {
+            TtsNegotiation::MissingCapability
+        } else {
+            TtsNegotiation::Accepted
+        }
+    }
+}
+
+/// Optional features a client may depend on, reported by `Negotiate` as a bit set.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TtsCapability {
+    /// `RegisterNoticeCb` and `TtsBeNotice`
+    Notices,
+    /// `SetParams`, `RegisterCbExt` and per-utterance overrides
+    Prosody,
+    /// `ListVoices` and `SetVoice`
+    Voices,
+    /// `TextToPhonemes`
+    Phonemes,
+    /// `StrToWavSync`
+    Sync,
+    /// queue modes and priorities on `StrToWavExt`
+    Queueing,
+    /// `Pause`, `Resume`, `Stop` and `Status`
+    Control,
+    /// `Authorize`
+    Authorization,
+}
+
+impl TtsCapability {
+    pub const ALL: [TtsCapability; 8] = [
+        TtsCapability::Notices, TtsCapability::Prosody, TtsCapability::Voices, TtsCapability::Phonemes,
+        TtsCapability::Sync, TtsCapability::Queueing, TtsCapability::Control, TtsCapability::Authorization,
+    ];
+
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    /// The capabilities of this server.
+    pub fn supported() -> u32 {
+        TtsCapability::ALL.iter().fold(0, |bits, c| bits | c.bit())
+    }
+}
+
+/// What the engine is doing, as reported by `Status`.
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TtsState {
+    Idle,
+    Synthesizing,
+    /// an abort has been requested and the engine hasn't confirmed it yet
+    Aborting,
+    /// `Pause` is holding speech, and nothing that outranks it is playing
+    Paused,
+    /// espeak has stopped responding in the middle of an utterance and never came back. Nothing
+    /// can be spoken until the server is restarted, so new utterances are dropped.
+    Hung,
+}
+
+/// Out-of-band notifications sent to the opcode registered with `RegisterNoticeCb`.
+/// The audio stream itself can only signal `End` or `Abort`, so anything more descriptive
+/// travels through here.
+#[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum TtsBeNotice {
+    /// Synthesis failed. `code` is the raw `espeak_ng_STATUS`, `message` is espeak-ng's
+    /// description of it. The audio stream receives an `Abort` alongside this.
+    Error { code: u32, message: String },
+    /// Utterance `id` was cut off, by `Stop` or by a newer utterance, after `offset` characters
+    /// of its text. The audio stream receives an `Abort` alongside this.
+    Aborted { id: u32, offset: u32 },
+    /// Utterance `id` was given up on because the engine produced no audio for `stalled_ms`; the
+    /// engine is rebuilt before the next utterance, or if it never returns, `Status` reports it as
+    /// `Hung`. `count` is the number of stalls since the server started. The audio stream receives
+    /// an `Abort` alongside this.
+    Stalled { id: u32, stalled_ms: u32, count: u32 },
+    /// The server is shutting down; this is the last message on the callback connection.
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum TtsVoiceSelect {
+    /// a voice name or identifier as reported by `ListVoices`, e.g. "en-us"
+    Name(String),
+    /// espeak's best match for the given properties; `None`/`Unspecified`/0 mean "don't care"
+    Properties {
+        /// language tag, e.g. "en-gb"
+        language: Option<String>,
+        gender: TtsGender,
+        /// age in years
+        age: u8,
+        /// picks the n-th best match, so that several distinct voices can be had for the same properties
+        variant: u8,
+    },
+}
+
+#[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsSetVoice {
+    pub voice: TtsVoiceSelect,
+    /// the `espeak_ng_STATUS` code if the voice couldn't be selected; filled in by the server
+    pub error: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsSetParams {
+    pub prosody: TtsProsody,
+    /// `None` keeps the current voice
+    pub voice: Option<TtsVoiceSelect>,
+    /// `None` keeps the current preset. Unlike the rest, this only takes effect with the next utterance.
+    pub latency: Option<TtsLatency>,
+    /// the first parameter that was out of its legal range; filled in by the server
+    pub rejected: Option<TtsParam>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum TtsPunctuation {
+    /// punctuation only shapes the intonation, which is espeak's default
+    None,
+    /// every punctuation character is spoken by name
+    All,
+}
+
+/// Settings that apply to a single utterance on top of the client's own.
+#[derive(Debug, Clone, Default, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsOverrides {
+    pub voice: Option<TtsVoiceSelect>,
+    pub prosody: TtsProsody,
+    pub punctuation: Option<TtsPunctuation>,
+}
+
+/// What a new utterance does to speech of the same priority that is already playing or queued.
+/// Speech of a lower priority is preempted rather than thrown away, and speech of a higher
+/// priority is never affected.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum TtsQueueMode {
+    /// abort whatever is playing, flush the queue and speak this right away; this is what
+    /// a plain `StrToWav` does
+    #[default]
+    Interrupt,
+    /// speak this once everything ahead of it in the queue has been spoken
+    Enqueue,
+    /// speak this right away if the engine is idle, otherwise don't speak it at all
+    DropIfBusy,
+}
+
+/// Utterances are spoken highest priority first. A new utterance that outranks the one playing
+/// preempts it at the next word; the preempted one then carries on from that word once
+/// everything that outranks it has been spoken.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum TtsPriority {
+    /// long reads, e.g. a document
+    Background,
+    /// direct responses to the user, e.g. key echo; this is what a plain `StrToWav` uses
+    #[default]
+    Interactive,
+    /// anything that must be heard right away, e.g. security alerts
+    Alert,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum TtsResult {
+    Completed,
+    /// cut off by `Stop` or by a newer utterance, possibly before it started
+    Aborted,
+    /// synthesis failed with this `espeak_ng_STATUS` code
+    Error(u32),
+    /// the engine stopped producing audio and the watchdog gave up on it
+    Stalled,
+}
+
+/// How a `StrToWavSync` utterance ended.
+#[derive(Debug, Copy, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsOutcome {
+    pub result: TtsResult,
+    /// samples delivered to the callback
+    pub samples: u32,
+    /// length of the delivered audio
+    pub duration_ms: u32,
+}
+
+#[derive(Debug, Clone, Default, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TtsStrToWav {
+    pub text: String,
+    pub overrides: TtsOverrides,
+    pub mode: TtsQueueMode,
+    pub priority: TtsPriority,
+    /// the first override that was out of its legal range; filled in by the server
+    pub rejected: Option<TtsParam>,
+    /// set if `DropIfBusy` turned the utterance away; filled in by the server
+    pub dropped: bool,
+    /// set if the client isn't allowed to have text spoken, see `Opcode::Authorize`; filled in by the server
+    pub denied: bool,
+    /// identifies the utterance in notices and status reports; filled in by the server
+    pub id: u32,
+    /// only for `StrToWavSync`; filled in by the server
+    pub outcome: Option<TtsOutcome>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revisions_outside_the_supported_range_are_turned_away() {
+        assert_eq!(TtsNegotiation::decide(MIN_PROTOCOL_REVISION - 1, 0, 0), TtsNegotiation::ClientTooOld);
+        assert_eq!(TtsNegotiation::decide(PROTOCOL_REVISION + 1, 0, 0), TtsNegotiation::ClientTooNew);
+        assert_eq!(TtsNegotiation::decide(MIN_PROTOCOL_REVISION, 0, 0), TtsNegotiation::Accepted);
+        assert_eq!(TtsNegotiation::decide(PROTOCOL_REVISION, 0, 0), TtsNegotiation::Accepted);
+    }
+
+    #[test]
+    fn every_required_capability_has_to_be_supported() {
+        let supported = TtsCapability::Notices.bit() | TtsCapability::Voices.bit();
+        assert_eq!(
+            TtsNegotiation::decide(PROTOCOL_REVISION, TtsCapability::Voices.bit(), supported),
+            TtsNegotiation::Accepted
+        );
+        assert_eq!(
+            TtsNegotiation::decide(PROTOCOL_REVISION, TtsCapability::Voices.bit() | TtsCapability::Sync.bit(), supported),
+            TtsNegotiation::MissingCapability
+        );
+        // a bit this server doesn't know of is a capability it lacks
+        assert_eq!(TtsNegotiation::decide(PROTOCOL_REVISION, 1 << 31, TtsCapability::supported()), TtsNegotiation::MissingCapability);
+    }
+
+    #[test]
+    fn the_revision_is_checked_before_the_capabilities() {
+        assert_eq!(TtsNegotiation::decide(PROTOCOL_REVISION + 1, 1 << 31, 0), TtsNegotiation::ClientTooNew);
+    }
+}
@@ -31,12 +31,27 @@ pub unsafe extern "C" fn libc_putchar(
     }
 }
 
+/// Copies a NUL-terminated string owned by espeak-ng into a Rust `String`. NULL yields an empty string.
+pub unsafe fn string_from_c(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        String::new()
+    } else {
+        std::ffi::CStr::from_ptr(ptr as *const core::ffi::c_char).to_string_lossy().into_owned()
+    }
+}
+
 pub fn reset_heap() {
     unsafe{C_HEAP.clear();}
 }
 
+/// Bytes currently handed out to the C code.
+pub fn heap_usage() -> usize {
+    unsafe{C_HEAP.iter().map(|region| region.len()).sum()}
+}
+
 static mut C_HEAP: Vec::<Vec::<u8>> = Vec::new();
-#[export_name = "malloc"]
+// not exported in the unit tests, which run on the host and would take over its allocator
+#[cfg_attr(not(test), export_name = "malloc")]
 pub unsafe extern "C" fn malloc(
     size: c_uint
 ) -> *mut c_void {
@@ -59,7 +74,7 @@ pub unsafe extern "C" fn malloc(
     ptr as *mut c_void
 }
 
-#[export_name = "free"]
+#[cfg_attr(not(test), export_name = "free")]
 pub unsafe extern "C" fn free(
     ptr: *mut c_void
 ) {
@@ -85,7 +100,7 @@ pub unsafe extern "C" fn free(
     }
 }
 
-#[export_name = "realloc"]
+#[cfg_attr(not(test), export_name = "realloc")]
 pub unsafe extern "C" fn realloc(
     ptr: *mut c_void,
     size: c_uint
@@ -148,6 +163,34 @@ pub unsafe extern "C" fn realloc(
 }
 
 pub type espeak_ng_STATUS_e = u32;
+pub const ENS_GROUP_MASK: espeak_ng_STATUS_e = 0x7000_0000;
+pub const ENS_GROUP_ERRNO: espeak_ng_STATUS_e = 0x0000_0000;
+pub const ENS_GROUP_ESPEAK_NG: espeak_ng_STATUS_e = 0x1000_0000;
+pub const ENS_OK: espeak_ng_STATUS_e = 0;
+pub const ENS_COMPILE_ERROR: espeak_ng_STATUS_e = 0x1000_01FF;
+pub const ENS_VERSION_MISMATCH: espeak_ng_STATUS_e = 0x1000_02FF;
+pub const ENS_FIFO_BUFFER_FULL: espeak_ng_STATUS_e = 0x1000_03FF;
+pub const ENS_NOT_INITIALIZED: espeak_ng_STATUS_e = 0x1000_04FF;
+pub const ENS_AUDIO_ERROR: espeak_ng_STATUS_e = 0x1000_05FF;
+pub const ENS_VOICE_NOT_FOUND: espeak_ng_STATUS_e = 0x1000_06FF;
+pub const ENS_MBROLA_NOT_FOUND: espeak_ng_STATUS_e = 0x1000_07FF;
+pub const ENS_MBROLA_VOICE_NOT_FOUND: espeak_ng_STATUS_e = 0x1000_08FF;
+pub const ENS_EVENT_BUFFER_FULL: espeak_ng_STATUS_e = 0x1000_09FF;
+pub const ENS_NOT_SUPPORTED: espeak_ng_STATUS_e = 0x1000_0AFF;
+pub const ENS_UNSUPPORTED_PHON_FORMAT: espeak_ng_STATUS_e = 0x1000_0BFF;
+pub const ENS_NO_SPECT_FRAMES: espeak_ng_STATUS_e = 0x1000_0CFF;
+pub const ENS_EMPTY_PHONEME_MANIFEST: espeak_ng_STATUS_e = 0x1000_0DFF;
+pub const ENS_SPEECH_STOPPED: espeak_ng_STATUS_e = 0x1000_0EFF;
+pub const ENS_UNKNOWN_PHONEME_FEATURE: espeak_ng_STATUS_e = 0x1000_0FFF;
+pub const ENS_UNKNOWN_TEXT_ENCODING: espeak_ng_STATUS_e = 0x1000_10FF;
+extern "C" {
+    /// lives in error.c; `length` is a size_t, which is 32 bits on our target
+    pub fn espeak_ng_GetStatusCodeMessage(
+        status: espeak_ng_STATUS_e,
+        buffer: *mut c_char,
+        length: c_uint,
+    );
+}
 extern "C" {
     pub fn espeak_ffi_synth(
         text: *const c_char,
@@ -158,12 +201,89 @@ extern "C" {
 extern "C" {
     pub fn espeak_ffi_sync();
 }
+
+pub const espeakCHARS_AUTO: c_int = 0;
+pub const espeakCHARS_UTF8: c_int = 1;
+/// `phonememode` bit for `espeak_TextToPhonemes`: IPA as UTF-8 instead of espeak's ASCII mnemonics
+pub const espeakPHONEMES_IPA: c_int = 0x02;
+extern "C" {
+    /// Translates one clause per call, advancing `*textptr`. `*textptr` is set to NULL once
+    /// the end of the text is reached. The returned string is owned by espeak-ng and is only
+    /// valid until the next call.
+    pub fn espeak_TextToPhonemes(
+        textptr: *mut *const c_void,
+        textmode: c_int,
+        phonememode: c_int,
+    ) -> *const c_char;
+}
 extern "C" {
     pub fn espeak_ng_Terminate();
 }
+
+#[repr(C)]
+pub struct espeak_VOICE {
+    /// a given name for this voice, UTF-8
+    pub name: *const c_char,
+    /// list of pairs of (byte) priority + (string) language, terminated by a 0 priority byte
+    pub languages: *const c_char,
+    /// the filename for this voice within espeak-ng-data/voices
+    pub identifier: *const c_char,
+    /// 0=none, 1=male, 2=female
+    pub gender: c_uchar,
+    /// 0=not specified, or age in years
+    pub age: c_uchar,
+    /// only used when passed as a parameter to espeak_ng_SetVoiceByProperties
+    pub variant: c_uchar,
+    pub xx1: c_uchar,
+    pub score: c_int,
+    pub spare: *mut c_void,
+}
+extern "C" {
+    /// Returns a NULL-terminated array of voices. Passing NULL for `voice_spec` lists all of them.
+    pub fn espeak_ListVoices(voice_spec: *mut espeak_VOICE) -> *const *const espeak_VOICE;
+}
+/// `espeak_PARAMETER` values for `espeak_ng_SetParameter`
+pub const espeakRATE: c_int = 1;
+pub const espeakVOLUME: c_int = 2;
+pub const espeakPITCH: c_int = 3;
+pub const espeakRANGE: c_int = 4;
+pub const espeakPUNCTUATION: c_int = 5;
+pub const espeakCAPITALS: c_int = 6;
+pub const espeakWORDGAP: c_int = 7;
+pub const espeakINTONATION: c_int = 9;
+pub const espeakEMPHASIS: c_int = 12;
+extern "C" {
+    /// `relative` is 0 for an absolute value, 1 for a value relative to the voice's default
+    pub fn espeak_ng_SetParameter(
+        parameter: c_int,
+        value: c_int,
+        relative: c_int,
+    ) -> espeak_ng_STATUS_e;
+}
+
+extern "C" {
+    /// `name` may be a voice name or a voice file identifier
+    pub fn espeak_ng_SetVoiceByName(name: *const c_char) -> espeak_ng_STATUS_e;
+}
+extern "C" {
+    /// Picks the best match for the `name`, `languages` (a single tag, without a priority byte),
+    /// `gender`, `age` and `variant` fields; unused fields are NULL or 0.
+    pub fn espeak_ng_SetVoiceByProperties(voice_selector: *mut espeak_VOICE) -> espeak_ng_STATUS_e;
+}
 extern "C" {
     pub fn espeak_ng_GetSampleRate() -> u32;
 }
+/// `espeak_ng_OUTPUT_MODE`: audio is handed to the callback, no audio device involved
+pub const ENOUTPUT_MODE_SYNCHRONOUS: c_int = 0x0001;
+extern "C" {
+    /// `buffer_length` is in ms, and sets how much audio each callback can carry; 0 means 60ms.
+    /// May be called again to resize the buffers, but only while no synthesis is in progress.
+    pub fn espeak_ng_InitializeOutput(
+        output_mode: c_int,
+        buffer_length: c_int,
+        device: *const c_char,
+    ) -> espeak_ng_STATUS_e;
+}
 extern "C" {
     pub fn ffi_sanity();
 }
@@ -172,24 +292,47 @@ extern "C" {
 }
 
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub union espeak_EVENT_id {
     pub number: i32,
     pub name: *const u8,
-    pub string: [u8; 0],
+    pub string: [u8; 8],
 }
+/// `espeak_EVENT_TYPE` values
+pub const espeakEVENT_LIST_TERMINATED: u32 = 0;
+pub const espeakEVENT_WORD: u32 = 1;
+pub const espeakEVENT_SENTENCE: u32 = 2;
+pub const espeakEVENT_MARK: u32 = 3;
+pub const espeakEVENT_PLAY: u32 = 4;
+pub const espeakEVENT_END: u32 = 5;
+pub const espeakEVENT_MSG_TERMINATED: u32 = 6;
+pub const espeakEVENT_PHONEME: u32 = 7;
+pub const espeakEVENT_SAMPLERATE: u32 = 8;
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct espeak_EVENT {
-    pub event_type: u32, // need to check if this is actually the case
+    pub event_type: u32,
     pub unique_identifier: u32,
+    /// number of characters from the start of the text, counting from 1
     pub text_position: i32,
+    /// word length, in characters (for espeakEVENT_WORD)
+    pub length: i32,
+    /// ms from the start of synthesis
     pub audio_position: i32,
+    /// samples from the start of synthesis
     pub sample: i32,
     pub user_data: *mut c_void,
     pub id: espeak_EVENT_id,
 }
+extern "C" {
+    /// replaces the callback given to `espeak_ffi_setup`
+    pub fn espeak_SetSynthCallback(
+        cb: extern "C" fn(samples: *const c_ushort, count: c_int, events: *const espeak_EVENT) -> i32,
+    );
+}
 extern "C" {
     pub fn espeak_ffi_setup(
-        cb: extern "C" fn(samples: *const c_ushort, count: c_int, event: espeak_EVENT) -> i32,
+        cb: extern "C" fn(samples: *const c_ushort, count: c_int, events: *const espeak_EVENT) -> i32,
         rate: i32,
      ) -> c_int;
 }
@@ -0,0 +1,211 @@
+use crate::api::{TtsLatency, TtsVoiceSelect};
+use crate::bindings::*;
+use crate::error::{to_cstring, EspeakError};
+use crate::session::{AccessPolicy, MixingPolicy};
+use crate::voices;
+use xous_tts_backend::MAX_WAV_BUF_SAMPLES;
+
+pub type SynthCallback = extern "C" fn(samples: *const c_ushort, count: c_int, events: *const espeak_EVENT) -> i32;
+
+/// How far the C heap may grow past its size right after initialization before the engine is
+/// torn down and rebuilt. espeak-ng leaks a little with every utterance; this bounds the leak
+/// without reloading the phoneme tables, voice and dictionary on every call.
+const HEAP_HEADROOM: usize = 512 * 1024;
+
+/// Spoken into the void at startup when `EngineConfig::primer` is set.
+const PRIMER_TEXT: &str = "Ready.";
+
+#[derive(Debug, Copy, Clone)]
+pub struct EngineConfig {
+    /// the voice used when the client hasn't picked one; it's loaded at startup so the first
+    /// utterance doesn't pay for it
+    pub default_voice: &'static str,
+    /// synthesize a short primer at startup and throw the audio away, which pulls in everything
+    /// the first real utterance would otherwise have to load
+    pub primer: bool,
+    /// the buffering preset used unless the client asks for another one
+    pub latency: TtsLatency,
+    /// how long an abort may take to be confirmed before the engine is considered stuck
+    pub abort_timeout: std::time::Duration,
+    /// how long synthesis may go without producing audio before the watchdog gives up on it
+    pub watchdog_timeout: std::time::Duration,
+    /// how speech from several client sessions shares the engine
+    pub mixing: MixingPolicy,
+    /// which processes may register a callback and have text spoken
+    pub access: AccessPolicy,
+}
+
+#[cfg(all(feature = "mixing-exclusive", feature = "mixing-preemptive"))]
+compile_error!("the mixing-exclusive and mixing-preemptive features are mutually exclusive");
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            default_voice: "en",
+            primer: true,
+            latency: TtsLatency::Balanced,
+            abort_timeout: std::time::Duration::from_millis(1500),
+            watchdog_timeout: std::time::Duration::from_millis(3000),
+            mixing: if cfg!(feature = "mixing-exclusive") {
+                MixingPolicy::Exclusive
+            } else if cfg!(feature = "mixing-preemptive") {
+                MixingPolicy::Preemptive
+            } else {
+                MixingPolicy::Queued
+            },
+            access: AccessPolicy::configured(),
+        }
+    }
+}
+
+extern "C" fn discard_cb(_samples: *const c_ushort, _count: c_int, _events: *const espeak_EVENT) -> i32 {
+    0
+}
+
+/// A long-lived espeak-ng instance. Only the synth thread may touch it, since espeak-ng keeps
+/// all of its state in globals.
+pub struct Engine {
+    callback: SynthCallback,
+    config: EngineConfig,
+    heap_baseline: usize,
+    /// the voice currently loaded, `None` being the configured default
+    voice: Option<TtsVoiceSelect>,
+    /// the buffering preset currently in effect
+    latency: TtsLatency,
+}
+
+/// The output buffer length for `latency`, in ms. A callback can't hand more than
+/// `MAX_WAV_BUF_SAMPLES` to the client, so that bounds every preset.
+fn buffer_ms(latency: TtsLatency) -> c_int {
+    let sample_rate = unsafe { espeak_ng_GetSampleRate() }.max(1);
+    let max_ms = (MAX_WAV_BUF_SAMPLES as u32 * 1000 / sample_rate) as c_int;
+    let ms = match latency {
+        TtsLatency::LowLatency => 10,
+        TtsLatency::Balanced => 60,
+        TtsLatency::Throughput => max_ms,
+    };
+    ms.min(max_ms)
+}
+
+impl Engine {
+    /// Loads the phoneme tables, default voice and dictionary, and runs the primer if configured.
+    /// This is the expensive part, so it happens once at server start, and again only when the
+    /// heap needs trimming.
+    pub fn new(callback: SynthCallback, config: EngineConfig, words_per_minute: u32) -> Engine {
+        let start = std::time::Instant::now();
+        unsafe { espeak_ffi_setup(callback, words_per_minute as i32) };
+        voices::set_voice(&TtsVoiceSelect::Name(String::from(config.default_voice))).unwrap_or_else(
+            |e| log::error!("couldn't load default voice {}: {}", config.default_voice, e)
+        );
+        let mut engine = Engine { callback, config, heap_baseline: 0, voice: None, latency: config.latency };
+        engine.resize_buffers(config.latency).unwrap_or_else(
+            |e| log::error!("couldn't size the output buffer for {:?}: {}", config.latency, e)
+        );
+        if config.primer {
+            engine.prime();
+        }
+        engine.heap_baseline = heap_usage();
+        log::info!("espeak warm-up took {}ms, sample rate {}, {} bytes of C heap",
+            start.elapsed().as_millis(), unsafe {espeak_ng_GetSampleRate()}, engine.heap_baseline);
+        engine
+    }
+
+    fn prime(&self) {
+        unsafe { espeak_SetSynthCallback(discard_cb) };
+        self.synthesize(PRIMER_TEXT, ::core::ptr::null_mut()).unwrap_or_else(
+            |e| log::warn!("primer failed: {}", e)
+        );
+        self.sync();
+        unsafe { espeak_SetSynthCallback(self.callback) };
+    }
+
+    /// Loads `voice`, or the configured default for `None`, unless it is already loaded.
+    /// On failure the engine keeps the voice it had.
+    pub fn set_voice(&mut self, voice: Option<&TtsVoiceSelect>) -> Result<(), EspeakError> {
+        if self.voice.as_ref() == voice {
+            return Ok(());
+        }
+        match voice {
+            Some(selection) => voices::set_voice(selection)?,
+            None => voices::set_voice(&TtsVoiceSelect::Name(String::from(self.config.default_voice)))?,
+        }
+        self.voice = voice.cloned();
+        Ok(())
+    }
+
+    /// Switches to the buffering preset `latency`, or the configured one for `None`, unless it's
+    /// already in effect. This reallocates espeak's output buffers, so only call it between utterances.
+    pub fn set_latency(&mut self, latency: Option<TtsLatency>) -> Result<(), EspeakError> {
+        let latency = latency.unwrap_or(self.config.latency);
+        if self.latency == latency {
+            return Ok(());
+        }
+        self.resize_buffers(latency)
+    }
+
+    fn resize_buffers(&mut self, latency: TtsLatency) -> Result<(), EspeakError> {
+        let ms = buffer_ms(latency);
+        EspeakError::check(unsafe {
+            espeak_ng_InitializeOutput(ENOUTPUT_MODE_SYNCHRONOUS, ms, ::core::ptr::null())
+        })?;
+        log::debug!("output buffer set to {}ms for {:?}", ms, latency);
+        self.latency = latency;
+        Ok(())
+    }
+
+    /// Speaks `text`, delivering the audio through the callback. Returns once the callback has
+    /// seen the end of synthesis or asked for it to stop. `user_data` is passed to the callback
+    /// in every event.
+    pub fn synthesize(&self, text: &str, user_data: *mut c_void) -> Result<(), EspeakError> {
+        let cstr = to_cstring(text)?;
+        let status = unsafe {
+            espeak_ffi_synth(
+                cstr.as_ptr() as *const c_char,
+                text.len() as c_uint,
+                user_data,
+            )
+        };
+        EspeakError::check(status)
+    }
+
+    /// Waits for the engine to go idle.
+    pub fn sync(&self) {
+        unsafe { espeak_ffi_sync() };
+    }
+
+    /// Rebuilds the engine if the C heap has grown too far, returning whether it did. The
+    /// caller has to push the client's settings into the fresh engine afterwards.
+    pub fn trim(&mut self, words_per_minute: u32) -> bool {
+        let usage = heap_usage();
+        if usage <= self.heap_baseline + HEAP_HEADROOM {
+            return false;
+        }
+        log::info!("C heap grew from {} to {} bytes, reinitializing espeak", self.heap_baseline, usage);
+        self.reset(words_per_minute);
+        true
+    }
+
+    /// Tears the engine down for good and frees the whole C heap.
+    pub fn shutdown(self) {
+        unsafe { espeak_ng_Terminate() };
+        reset_heap();
+        log::info!("espeak shut down");
+    }
+
+    /// Tears the engine down, frees the whole C heap and builds it up again from scratch. The
+    /// caller has to push the client's settings into the fresh engine afterwards.
+    ///
+    /// The leaked blocks can't be told apart from the ones espeak's globals still point at, so
+    /// there's no freeing them piecemeal; only `espeak_ng_Terminate` leaves nothing live on the
+    /// heap. The primer is skipped, since whichever utterance triggered the reset is waiting on
+    /// it, and the baseline from startup is kept, as it's the one measured with everything loaded.
+    pub fn reset(&mut self, words_per_minute: u32) {
+        unsafe { espeak_ng_Terminate() };
+        // espeak leaks memory. You need to do this or else we run out of space.
+        reset_heap();
+        let (config, heap_baseline) = (self.config, self.heap_baseline);
+        *self = Engine::new(self.callback, EngineConfig { primer: false, ..config }, words_per_minute);
+        self.config = config;
+        self.heap_baseline = heap_baseline;
+    }
+}
@@ -0,0 +1,147 @@
+use crate::bindings::*;
+
+/// Decoded form of the `espeak_ng_STATUS` codes returned by the espeak-ng API.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EspeakError {
+    /// codes 0-255 are passed through from the C library's `errno`
+    Errno(u32),
+    CompileError,
+    VersionMismatch,
+    FifoBufferFull,
+    NotInitialized,
+    AudioError,
+    VoiceNotFound,
+    MbrolaNotFound,
+    MbrolaVoiceNotFound,
+    EventBufferFull,
+    NotSupported,
+    UnsupportedPhonFormat,
+    NoSpectFrames,
+    EmptyPhonemeManifest,
+    /// synthesis was stopped by the callback returning 1. This is how both End and Abort
+    /// terminate synthesis in this server, so it is usually not a failure.
+    SpeechStopped,
+    UnknownPhonemeFeature,
+    UnknownTextEncoding,
+    /// a code that this version of the bindings doesn't know about
+    Unknown(u32),
+}
+
+impl EspeakError {
+    /// Turns a raw status code into a `Result`; `ENS_OK` is the only success value.
+    pub fn check(status: espeak_ng_STATUS_e) -> Result<(), EspeakError> {
+        let err = match status {
+            ENS_OK => return Ok(()),
+            ENS_COMPILE_ERROR => EspeakError::CompileError,
+            ENS_VERSION_MISMATCH => EspeakError::VersionMismatch,
+            ENS_FIFO_BUFFER_FULL => EspeakError::FifoBufferFull,
+            ENS_NOT_INITIALIZED => EspeakError::NotInitialized,
+            ENS_AUDIO_ERROR => EspeakError::AudioError,
+            ENS_VOICE_NOT_FOUND => EspeakError::VoiceNotFound,
+            ENS_MBROLA_NOT_FOUND => EspeakError::MbrolaNotFound,
+            ENS_MBROLA_VOICE_NOT_FOUND => EspeakError::MbrolaVoiceNotFound,
+            ENS_EVENT_BUFFER_FULL => EspeakError::EventBufferFull,
+            ENS_NOT_SUPPORTED => EspeakError::NotSupported,
+            ENS_UNSUPPORTED_PHON_FORMAT => EspeakError::UnsupportedPhonFormat,
+            ENS_NO_SPECT_FRAMES => EspeakError::NoSpectFrames,
+            ENS_EMPTY_PHONEME_MANIFEST => EspeakError::EmptyPhonemeManifest,
+            ENS_SPEECH_STOPPED => EspeakError::SpeechStopped,
+            ENS_UNKNOWN_PHONEME_FEATURE => EspeakError::UnknownPhonemeFeature,
+            ENS_UNKNOWN_TEXT_ENCODING => EspeakError::UnknownTextEncoding,
+            code if (code & ENS_GROUP_MASK) == ENS_GROUP_ERRNO => EspeakError::Errno(code),
+            code => EspeakError::Unknown(code),
+        };
+        Err(err)
+    }
+
+    /// The raw `espeak_ng_STATUS` value, suitable for passing over IPC.
+    pub fn code(&self) -> u32 {
+        match self {
+            EspeakError::Errno(code) => *code,
+            EspeakError::CompileError => ENS_COMPILE_ERROR,
+            EspeakError::VersionMismatch => ENS_VERSION_MISMATCH,
+            EspeakError::FifoBufferFull => ENS_FIFO_BUFFER_FULL,
+            EspeakError::NotInitialized => ENS_NOT_INITIALIZED,
+            EspeakError::AudioError => ENS_AUDIO_ERROR,
+            EspeakError::VoiceNotFound => ENS_VOICE_NOT_FOUND,
+            EspeakError::MbrolaNotFound => ENS_MBROLA_NOT_FOUND,
+            EspeakError::MbrolaVoiceNotFound => ENS_MBROLA_VOICE_NOT_FOUND,
+            EspeakError::EventBufferFull => ENS_EVENT_BUFFER_FULL,
+            EspeakError::NotSupported => ENS_NOT_SUPPORTED,
+            EspeakError::UnsupportedPhonFormat => ENS_UNSUPPORTED_PHON_FORMAT,
+            EspeakError::NoSpectFrames => ENS_NO_SPECT_FRAMES,
+            EspeakError::EmptyPhonemeManifest => ENS_EMPTY_PHONEME_MANIFEST,
+            EspeakError::SpeechStopped => ENS_SPEECH_STOPPED,
+            EspeakError::UnknownPhonemeFeature => ENS_UNKNOWN_PHONEME_FEATURE,
+            EspeakError::UnknownTextEncoding => ENS_UNKNOWN_TEXT_ENCODING,
+            EspeakError::Unknown(code) => *code,
+        }
+    }
+
+    /// The human-readable description, as generated by espeak-ng's own `error.c`.
+    pub fn message(&self) -> String {
+        if *self == EspeakError::SpeechStopped {
+            // error.c has no string for this one and would report it as "Unspecified error"
+            return String::from("Speech synthesis was stopped");
+        }
+        let mut buf = [0u8; 128];
+        unsafe {
+            espeak_ng_GetStatusCodeMessage(self.code(), buf.as_mut_ptr() as *mut c_char, buf.len() as c_uint);
+        }
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+}
+
+/// Copies `text` into a C string for espeak-ng. An interior NUL can't be passed to C; EINVAL is
+/// the closest status code espeak-ng has for that.
+pub fn to_cstring(text: &str) -> Result<std::ffi::CString, EspeakError> {
+    std::ffi::CString::new(text).map_err(|_| EspeakError::Errno(22))
+}
+
+impl std::fmt::Display for EspeakError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (0x{:08x})", self.message(), self.code())
+    }
+}
+
+impl std::error::Error for EspeakError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_and_code_round_trip() {
+        let errors = [
+            EspeakError::Errno(22),
+            EspeakError::CompileError,
+            EspeakError::VersionMismatch,
+            EspeakError::FifoBufferFull,
+            EspeakError::NotInitialized,
+            EspeakError::AudioError,
+            EspeakError::VoiceNotFound,
+            EspeakError::MbrolaNotFound,
+            EspeakError::MbrolaVoiceNotFound,
+            EspeakError::EventBufferFull,
+            EspeakError::NotSupported,
+            EspeakError::UnsupportedPhonFormat,
+            EspeakError::NoSpectFrames,
+            EspeakError::EmptyPhonemeManifest,
+            EspeakError::SpeechStopped,
+            EspeakError::UnknownPhonemeFeature,
+            EspeakError::UnknownTextEncoding,
+            EspeakError::Unknown(ENS_GROUP_ESPEAK_NG | 0xFFFF),
+        ];
+        for err in errors {
+            assert_eq!(EspeakError::check(err.code()), Err(err));
+        }
+        assert_eq!(EspeakError::check(ENS_OK), Ok(()));
+    }
+
+    #[test]
+    fn interior_nul_is_einval() {
+        assert_eq!(to_cstring("a\0b").unwrap_err(), EspeakError::Errno(22));
+        assert_eq!(to_cstring("ab").unwrap().as_bytes(), b"ab");
+    }
+}
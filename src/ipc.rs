@@ -0,0 +1,165 @@
+use rkyv::api::high::{HighDeserializer, HighSerializer, HighValidator};
+use rkyv::bytecheck::CheckBytes;
+use rkyv::rancor;
+use rkyv::ser::allocator::ArenaHandle;
+use rkyv::util::AlignedVec;
+use xous::{MemoryAddress, MemoryMessage};
+
+/// Why a request lent to us couldn't be read.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// the client claims to have used more bytes than it lent
+    Overrun { used: usize, len: usize },
+    /// the bytes aren't a valid archive of the expected type
+    Invalid(rancor::Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Overrun { used, len } => write!(f, "{} bytes used of the {} lent", used, len),
+            DecodeError::Invalid(e) => write!(f, "malformed request: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Reads the request a client lent us in `mem`. Unlike `Buffer::to_original`, this doesn't take
+/// the client's word for anything: the offset has to lie within the lent memory, and the archive
+/// is validated before it's deserialized, so a malformed request is an error rather than a read
+/// out of bounds.
+pub fn decode<T>(mem: &MemoryMessage) -> Result<T, DecodeError>
+where
+    T: rkyv::Archive,
+    T::Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>
+        + rkyv::Deserialize<T, HighDeserializer<rancor::Error>>,
+{
+    let used = mem.offset.map_or(0, |offset| offset.get());
+    decode_from(unsafe { mem.buf.as_slice::<u8>() }, used)
+}
+
+/// `decode`, over the first `used` bytes of `bytes`.
+fn decode_from<T>(bytes: &[u8], used: usize) -> Result<T, DecodeError>
+where
+    T: rkyv::Archive,
+    T::Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>
+        + rkyv::Deserialize<T, HighDeserializer<rancor::Error>>,
+{
+    if used > bytes.len() {
+        return Err(DecodeError::Overrun { used, len: bytes.len() });
+    }
+    rkyv::from_bytes::<T, rancor::Error>(&bytes[..used]).map_err(DecodeError::Invalid)
+}
+
+/// Why a reply couldn't be written back to the client.
+#[derive(Debug)]
+pub enum ReplyError {
+    /// the reply needs more bytes than the client lent
+    TooLarge { needed: usize, len: usize },
+    /// the reply couldn't be serialized
+    Invalid(rancor::Error),
+}
+
+impl std::fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplyError::TooLarge { needed, len } => write!(f, "reply needs {} bytes, {} were lent", needed, len),
+            ReplyError::Invalid(e) => write!(f, "couldn't serialize reply: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+/// Writes `value` into `mem` as the answer to the client's request. Unlike `Buffer::replace`,
+/// which panics when the reply outgrows the lent memory, the reply is serialized on our side
+/// first and only copied over if it fits. If it doesn't, `mem` goes back with no valid data, as
+/// with `refuse`, so the client doesn't read its own request back as the answer; a caller that
+/// can shrink the reply may try again.
+pub fn encode<T>(mem: &mut MemoryMessage, value: &T) -> Result<(), ReplyError>
+where
+    T: for<'a> rkyv::Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, rancor::Error>>,
+{
+    match encode_into(value, unsafe { mem.buf.as_slice_mut::<u8>() }) {
+        Ok(used) => {
+            mem.offset = MemoryAddress::new(used);
+            Ok(())
+        }
+        Err(e) => {
+            mem.offset = None;
+            mem.valid = None;
+            Err(e)
+        }
+    }
+}
+
+/// Serializes `value` into the start of `buf`, returning how many bytes it took. `buf` is left
+/// untouched if it's too small.
+fn encode_into<T>(value: &T, buf: &mut [u8]) -> Result<usize, ReplyError>
+where
+    T: for<'a> rkyv::Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, rancor::Error>>,
+{
+    let bytes = rkyv::to_bytes::<rancor::Error>(value).map_err(ReplyError::Invalid)?;
+    if bytes.len() > buf.len() {
+        return Err(ReplyError::TooLarge { needed: bytes.len(), len: buf.len() });
+    }
+    buf[..bytes.len()].copy_from_slice(&bytes);
+    Ok(bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::*;
+
+    #[test]
+    fn a_well_formed_request_decodes() {
+        let bytes = rkyv::to_bytes::<rancor::Error>(&TtsVoiceSelect::Name(String::from("en-us"))).unwrap();
+        let decoded = decode_from::<TtsVoiceSelect>(&bytes, bytes.len()).unwrap();
+        assert_eq!(decoded, TtsVoiceSelect::Name(String::from("en-us")));
+    }
+
+    #[test]
+    fn an_offset_past_the_lent_memory_is_rejected() {
+        let bytes = rkyv::to_bytes::<rancor::Error>(&TtsLatency::Balanced).unwrap();
+        assert!(matches!(
+            decode_from::<TtsLatency>(&bytes, bytes.len() + 1),
+            Err(DecodeError::Overrun { .. })
+        ));
+    }
+
+    #[test]
+    fn a_malformed_archive_is_rejected() {
+        let mut bytes = rkyv::to_bytes::<rancor::Error>(&TtsLatency::Balanced).unwrap();
+        bytes.as_mut_slice().fill(0xff);
+        assert!(matches!(decode_from::<TtsLatency>(&bytes, bytes.len()), Err(DecodeError::Invalid(_))));
+        assert!(matches!(decode_from::<TtsLatency>(&bytes, 0), Err(DecodeError::Invalid(_))));
+    }
+
+    fn phonemes(text: &str) -> TtsPhonemes {
+        TtsPhonemes { text: String::from(text), format: PhonemeFormat::Mnemonics, phonemes: String::new(), error: None }
+    }
+
+    #[test]
+    fn a_reply_that_fits_can_be_read_back() {
+        // the memory a client lends is page aligned, which checked access relies on
+        let mut buf = AlignedVec::<16>::new();
+        buf.resize(256, 0);
+        let used = encode_into(&phonemes("hello"), buf.as_mut_slice()).unwrap();
+        let decoded = decode_from::<TtsPhonemes>(&buf, used).unwrap();
+        assert_eq!(decoded.text, "hello");
+    }
+
+    #[test]
+    fn a_reply_too_large_for_the_lent_memory_is_an_error() {
+        let mut buf = AlignedVec::<16>::new();
+        buf.resize(64, 0);
+        let long = "x".repeat(100);
+        assert!(matches!(
+            encode_into(&phonemes(&long), buf.as_mut_slice()),
+            Err(ReplyError::TooLarge { len: 64, .. })
+        ));
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+}
@@ -4,23 +4,105 @@
 extern crate espeak_sys;
 extern crate xous_tts_backend;
 use xous_tts_backend::*;
-use xous::{SID, CID, send_message, Message};
+use xous::{SID, CID, PID, send_message, Message};
 use xous_ipc::Buffer;
 
 pub mod bindings;
 pub use bindings::*;
 mod logger;
 use logger::*;
+mod error;
+use error::*;
+mod api;
+use api::*;
+mod voices;
+mod params;
+use params::*;
+mod engine;
+use engine::*;
+mod queue;
+use queue::*;
+mod abort;
+use abort::*;
+mod session;
+use session::*;
+mod ipc;
+use ipc::*;
 
-use std::sync::atomic::{AtomicBool, Ordering, AtomicI32};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use num_traits::*;
 
-static mut CB: Option<Callback> = None;
 static TTS_RUNNING: AtomicBool = AtomicBool::new(false);
 static TTS_SHOULD_ABORT: AtomicBool = AtomicBool::new(false);
+/// Set when the client changes its settings mid-utterance. The callback then cuts the audio at
+/// the next sentence boundary, so the synth thread can apply them and carry on from there.
+static PARAMS_CHANGED: AtomicBool = AtomicBool::new(false);
+/// Set when an utterance of a higher priority is queued. The callback then cuts the audio at the
+/// next word boundary, so the synth thread can put the rest of the utterance back in the queue.
+static PREEMPT_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Set by `Pause`. Like a preemption, the callback cuts the audio at the next word boundary and
+/// the rest of the utterance goes back in the queue, where it is held until `Resume`.
+static PAUSE_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// How far into the current utterance's text the audio delivered so far goes, in characters,
+/// as of the start of the last word that went out.
+static SPOKEN_TO: AtomicU32 = AtomicU32::new(0);
+/// When the engine last delivered audio, while it is synthesizing; `None` between calls into
+/// espeak, and while the client holds on to a buffer. Checked by the watchdog thread.
+static LAST_PROGRESS: Mutex<Option<Instant>> = Mutex::new(None);
+/// Set by the watchdog once it has given up on the current utterance. The callback then stops
+/// synthesis as soon as it gets control again, and the synth thread tells the client and
+/// rebuilds the engine.
+static WATCHDOG_TRIPPED: AtomicBool = AtomicBool::new(false);
+static WATCHDOG_EVENTS: AtomicU32 = AtomicU32::new(0);
+/// How long the engine had gone without progress when the watchdog tripped, in ms.
+static STALLED_MS: AtomicU32 = AtomicU32::new(0);
+/// Set by the watchdog if espeak doesn't even return after it tripped. The synth thread is stuck
+/// inside espeak then, so new utterances are turned away instead of piling up unspoken.
+static ENGINE_HUNG: AtomicBool = AtomicBool::new(false);
 
+/// What the callback works with while an utterance is being spoken. It belongs to the synth
+/// thread, which hands it to espeak as the `user_data` of every synthesis call, so the callback
+/// finds it in the events of each chunk.
+#[derive(Default)]
+struct SynthState {
+    /// the callback of the session whose utterance is being spoken
+    cb: Option<Callback>,
+    /// the session `cb` belongs to
+    session: Option<PID>,
+    /// sessions whose callback couldn't be reached, which means the client is gone. Their speech
+    /// is stopped, and the synth thread drops them once it is done with what it's working on.
+    unreachable: Vec<PID>,
+    /// samples produced so far by the current call into espeak
+    segment_samples: i32,
+    /// text position (in characters, counting from 1) at which the callback cut synthesis short
+    restart_at: Option<i32>,
+    /// audio held back until there's enough for a full `samples_per_cb` block
+    pending: Vec<u16>,
+    /// characters of the current utterance's text that precede the segment being synthesized
+    segment_base: u32,
+    /// set once an `Abort` has gone out for the current utterance
+    aborted: bool,
+    /// samples of the current utterance handed to the client so far
+    delivered: u32,
+}
+
+impl SynthState {
+    /// Notes that `session`'s callback couldn't be reached.
+    fn lost(&mut self, session: Option<PID>, e: xous::Error) {
+        log::error!("couldn't reach the callback of {:?}, the client seems to be gone: {:?}", session, e);
+        if let Some(pid) = session.filter(|pid| !self.unreachable.contains(pid)) {
+            self.unreachable.push(pid);
+        }
+    }
+
+    /// True if the callback of the utterance being spoken couldn't be reached.
+    fn cb_lost(&self) -> bool {
+        self.session.is_some_and(|pid| self.unreachable.contains(&pid))
+    }
+}
 
 /*
    The callback function is of the form:
@@ -40,45 +122,90 @@ int SynthCallback(short *wav, int numsamples, espeak_EVENT *events);
 
    Callback returns: 0=continue synthesis,  1=abort synthesis.
 */
-extern "C" fn tts_cb(samples: *const c_ushort, count: c_int, _event: espeak_EVENT) -> i32 {
-    if let Some(cb) = unsafe{CB} {
-        let mut tts_data = TtsBackendData {
-            data: [0u16; MAX_WAV_BUF_SAMPLES],
-            len: count as u32,
-            control: None,
-        };
-        if samples != ::core::ptr::null::<c_ushort>() {
-            if count > 0 {
-                let samps: &[u16] = unsafe {
-                    core::slice::from_raw_parts::<u16>(samples, count as usize)
-                };
-                for (&src, dst) in samps.iter().zip(tts_data.data.iter_mut()) {
-                    *dst = src;
-                }
-            } else {
-                // we just got a 0-count packet that is probably a sentence metadata event. ignore and move on
-            }
+extern "C" fn tts_cb(samples: *const c_ushort, count: c_int, events: *const espeak_EVENT) -> i32 {
+    // every event carries the `user_data` given to `Engine::synthesize`, which is the synth thread's state
+    let state = match unsafe { events.as_ref() } {
+        Some(event) if !event.user_data.is_null() => unsafe { &mut *(event.user_data as *mut SynthState) },
+        _ => {
+            log::error!("callback without synthesis state, stopping");
+            return 1;
+        }
+    };
+    if WATCHDOG_TRIPPED.load(Ordering::SeqCst) {
+        // the synth thread tells the client once espeak has returned
+        return 1;
+    }
+    if state.cb_lost() {
+        // nobody is listening any more
+        state.aborted = true;
+        return 1;
+    }
+    let mut count = count.max(0);
+    let progress = samples.is_null() || count > 0;
+    let chunk_start = state.segment_samples;
+    state.segment_samples += count;
+    let mut restart = false;
+    // a preemption or pause can't wait for the end of the sentence, so it cuts at the next word instead
+    let boundary = if PREEMPT_REQUESTED.load(Ordering::SeqCst) || PAUSE_REQUESTED.load(Ordering::SeqCst) {
+        Some(espeakEVENT_WORD)
+    } else if PARAMS_CHANGED.load(Ordering::SeqCst) {
+        Some(espeakEVENT_SENTENCE)
+    } else {
+        None
+    };
+    if let Some(event_type) = boundary.filter(|_| !samples.is_null() && !TTS_SHOULD_ABORT.load(Ordering::SeqCst)) {
+        if let Some((event, offset)) = unsafe { find_boundary(events, event_type, chunk_start, count) } {
+            // only send the audio up to the boundary; the synth thread picks up the rest from there
+            count = offset as c_int;
+            state.restart_at = Some(event.text_position);
+            restart = true;
+        }
+    }
+    unsafe { track_progress(events, state.segment_base, chunk_start + count) };
+    if !samples.is_null() {
+        state.delivered += count as u32;
+    }
+    if state.cb.is_some() {
+        let audio: &[u16] = if !samples.is_null() && count > 0 {
+            unsafe { core::slice::from_raw_parts::<u16>(samples, count as usize) }
         } else {
+            // either a 0-count packet that is probably a sentence metadata event, or the end of synthesis
+            &[]
+        };
+        let mut control = if samples.is_null() {
             // wave is null, which means we hit the end of synthesis
-            tts_data.control = Some(TtsBeControl::End);
-        }
+            Some(TtsBeControl::End)
+        } else {
+            None
+        };
         // check to see if we should be aborting synthesis, otherwise move on.
         if TTS_SHOULD_ABORT.load(Ordering::SeqCst) {
             // this will override the End signal, but I think that's OK if we Abort in case of an End, they are ultimately the same path
-            tts_data.control = Some(TtsBeControl::Abort);
+            control = Some(TtsBeControl::Abort);
+            state.aborted = true;
+        }
+        if progress {
+            // the client may hold on to the buffer for as long as it likes, that's not the engine stalling
+            *LAST_PROGRESS.lock().unwrap() = None;
         }
-        if count > 0 || tts_data.control.is_some() {
-            // only generate a message if we have some data to send, or a control state update
-            let buf = Buffer::into_buf(tts_data).expect("couldn't convert buffer");
-            buf.lend(cb.cid, cb.op).expect("couldn't transmit memory message");
+        send_audio(state, audio, control);
+        if progress {
+            *LAST_PROGRESS.lock().unwrap() = Some(Instant::now());
         }
-        match tts_data.control {
+        match control {
+            None if restart => 1, // stop here, the synth thread picks up again at `restart_at`
             None => 0, // keep synthesizing if no error codes are set
             _ => 1, // abort or end synthesis by returning 1
         }
     } else {
+        if progress {
+            *LAST_PROGRESS.lock().unwrap() = Some(Instant::now());
+        }
         // even if we have no CB set, check for an abort signal and pass it on
         if TTS_SHOULD_ABORT.load(Ordering::SeqCst) {
+            state.aborted = true;
+            1 // abort synthesis
+        } else if restart {
             1 // abort synthesis
         } else {
             0 // continue synthensis
@@ -86,21 +213,658 @@ extern "C" fn tts_cb(samples: *const c_ushort, count: c_int, _event: espeak_EVEN
     }
 }
 
-#[derive(Copy, Clone)]
-struct Callback {
-    // the sid field probably won't ever be used, but we keep it around because it's impossible to recover once lost
-    pub _sid: SID,
-    pub cid: CID,
-    pub op: u32,
-    #[allow(dead_code)] // for now this is a reserved field
-    pub samples_per_cb: Option<u32>,
+/// Sends one block of audio. Only fails if the client can't be reached.
+fn send_block(cb: &Callback, samples: &[u16], control: Option<TtsBeControl>) -> Result<(), xous::Error> {
+    if samples.is_empty() && control.is_none() {
+        // only generate a message if we have some data to send, or a control state update
+        return Ok(());
+    }
+    let mut tts_data = TtsBackendData {
+        data: [0u16; MAX_WAV_BUF_SAMPLES],
+        len: samples.len() as u32,
+        control,
+    };
+    tts_data.data[..samples.len()].copy_from_slice(samples);
+    let buf = match Buffer::into_buf(tts_data) {
+        Ok(buf) => buf,
+        Err(e) => {
+            log::error!("couldn't serialize audio, dropping {} samples: {:?}", samples.len(), e);
+            return Ok(());
+        }
+    };
+    buf.lend(cb.cid, cb.op).map(|_| ())
+}
+
+/// Delivers audio to the client. If it asked for a `samples_per_cb`, the audio is re-chunked into
+/// blocks of exactly that size, and the remainder is held back for the next call: `End` flushes
+/// it as a final, shorter block, `Abort` throws it away. Otherwise chunks are passed on as
+/// espeak produces them, split only if they wouldn't fit into a `TtsBackendData`.
+fn send_audio(state: &mut SynthState, samples: &[u16], control: Option<TtsBeControl>) {
+    let Some(cb) = state.cb.filter(|_| !state.cb_lost()) else {
+        return;
+    };
+    let send = |block: &[u16], control| send_block(&cb, block, control);
+    if let Err(e) = send_chunks(cb.samples_per_cb, &mut state.pending, samples, control, send) {
+        state.lost(state.session, e);
+    }
+}
+
+/// Cuts `samples` into the blocks `send_audio` describes and hands each one to `send`, stopping
+/// at the first error.
+fn send_chunks<E>(
+    samples_per_cb: Option<u32>,
+    pending: &mut Vec<u16>,
+    samples: &[u16],
+    control: Option<TtsBeControl>,
+    mut send: impl FnMut(&[u16], Option<TtsBeControl>) -> Result<(), E>,
+) -> Result<(), E> {
+    let block_len = match samples_per_cb {
+        Some(n) => (n as usize).clamp(1, MAX_WAV_BUF_SAMPLES),
+        None => {
+            let mut chunks = samples.chunks(MAX_WAV_BUF_SAMPLES).peekable();
+            while let Some(chunk) = chunks.next() {
+                send(chunk, if chunks.peek().is_none() { control } else { None })?;
+            }
+            if samples.is_empty() {
+                send(&[], control)?;
+            }
+            return Ok(());
+        }
+    };
+    if let Some(TtsBeControl::Abort) = control {
+        pending.clear();
+        return send(&[], control);
+    }
+    pending.extend_from_slice(samples);
+    let mut sent = 0;
+    while pending.len() - sent >= block_len {
+        send(&pending[sent..sent + block_len], None)?;
+        sent += block_len;
+    }
+    pending.drain(..sent);
+    if control.is_some() {
+        send(pending, control)?;
+        pending.clear();
+    }
+    Ok(())
+}
+
+/// Finds the first event of `event_type` in a chunk's event list that isn't at the very start of
+/// the segment, along with its offset in samples into the chunk that starts at `chunk_start`.
+unsafe fn find_boundary(events: *const espeak_EVENT, event_type: u32, chunk_start: i32, count: i32) -> Option<(espeak_EVENT, usize)> {
+    if events.is_null() {
+        return None;
+    }
+    let mut event = events;
+    while (*event).event_type != espeakEVENT_LIST_TERMINATED {
+        if (*event).event_type == event_type && (*event).sample > 0 {
+            let offset = ((*event).sample - chunk_start).clamp(0, count);
+            return Some((*event, offset as usize));
+        }
+        event = event.add(1);
+    }
+    None
+}
+
+/// Advances `SPOKEN_TO` to the last word in a chunk's event list that starts before `limit`, the
+/// end of the audio that is actually being delivered, in samples from the start of the segment.
+/// `segment_base` is the number of characters of the utterance that precede the segment.
+unsafe fn track_progress(events: *const espeak_EVENT, segment_base: u32, limit: i32) {
+    if events.is_null() {
+        return;
+    }
+    let mut event = events;
+    while (*event).event_type != espeakEVENT_LIST_TERMINATED {
+        if (*event).event_type == espeakEVENT_WORD && (*event).sample < limit {
+            SPOKEN_TO.store(segment_base + ((*event).text_position.max(1) - 1) as u32, Ordering::SeqCst);
+        }
+        event = event.add(1);
+    }
+}
+
+/// Sends `notice` if the client asked for notices. Only fails if the client can't be reached.
+fn send_notice(cb: &Callback, notice: TtsBeNotice) -> Result<(), xous::Error> {
+    if let Some(op) = cb.notice_op {
+        match Buffer::into_buf(notice) {
+            Ok(buf) => return buf.lend(cb.cid, op).map(|_| ()),
+            Err(e) => log::error!("couldn't serialize notice: {:?}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Sends `notice` to the callback of the utterance being spoken.
+fn notify(state: &mut SynthState, notice: TtsBeNotice) {
+    let Some(cb) = state.cb.filter(|_| !state.cb_lost()) else {
+        return;
+    };
+    if let Err(e) = send_notice(&cb, notice) {
+        state.lost(state.session, e);
+    }
+}
+
+/// Logs a synthesis failure and tells the client about it, so a failed utterance doesn't
+/// just look like silence: the audio stream gets an `Abort`, and the notice callback (if
+/// any) gets the error code and espeak-ng's description of it.
+fn report_error(state: &mut SynthState, err: EspeakError) {
+    log::error!("espeak synthesis failed: {}", err);
+    send_audio(state, &[], Some(TtsBeControl::Abort));
+    notify(state, TtsBeNotice::Error { code: err.code(), message: err.message() });
+}
+
+/// Runs `espeak_TextToPhonemes` over the whole of `text`. The engine must already be set up.
+fn text_to_phonemes(text: &str, format: PhonemeFormat) -> Result<String, EspeakError> {
+    let cstr = to_cstring(text)?;
+    let mode = match format {
+        PhonemeFormat::Ipa => espeakPHONEMES_IPA,
+        PhonemeFormat::Mnemonics => 0,
+    };
+    let mut textptr = cstr.as_ptr() as *const c_void;
+    let mut phonemes = String::new();
+    // espeak translates one clause per call and NULLs out the pointer when it's done
+    while !textptr.is_null() {
+        let clause = unsafe { espeak_TextToPhonemes(&mut textptr, espeakCHARS_UTF8, mode) };
+        if clause.is_null() {
+            break;
+        }
+        let clause = unsafe { string_from_c(clause) };
+        let clause = clause.trim();
+        if !clause.is_empty() {
+            if !phonemes.is_empty() {
+                phonemes.push(' ');
+            }
+            phonemes.push_str(clause);
+        }
+    }
+    Ok(phonemes)
+}
+
+/// Pushes the session's prosody and voice (the configured default if it hasn't picked one) into the engine.
+/// A voice that came in through `SetParams` hasn't been checked yet and may turn out not to
+/// exist; it is then dropped, the engine keeps the voice it had, and the client is told why.
+fn apply_settings(engine: &mut Engine, state: &mut SynthState, params: &SpeechParams, sessions: &Mutex<Sessions>, session: Option<PID>) {
+    params.apply().unwrap_or_else(
+        |e| log::error!("couldn't apply speech parameters {:?}: {}", params, e)
+    );
+    let voice = sessions.lock().unwrap().voice(session);
+    if let Err(e) = engine.set_voice(voice.as_ref()) {
+        log::warn!("couldn't select voice {:?}: {}", voice, e);
+        // a failed selection leaves the engine's current voice in place
+        let callback = session.and_then(|pid| {
+            let mut sessions = sessions.lock().unwrap();
+            let session = sessions.entry(pid);
+            session.voice = None;
+            session.callback
+        });
+        if let Some(cb) = callback {
+            if let Err(e) = send_notice(&cb, TtsBeNotice::Error { code: e.code(), message: e.message() }) {
+                state.lost(session, e);
+            }
+        }
+    }
+}
+
+/// Layers an utterance's own settings over the client's. The prosody was range checked when the
+/// request came in; the voice can only be checked here, and if it doesn't exist the utterance is
+/// spoken in the client's voice and the client is told why.
+fn apply_overrides(engine: &mut Engine, state: &mut SynthState, params: &SpeechParams, overrides: &TtsOverrides) {
+    if overrides.prosody != TtsProsody::default() {
+        match params.merged(&overrides.prosody) {
+            Ok(merged) => merged.apply().unwrap_or_else(
+                |e| log::error!("couldn't apply speech parameters {:?}: {}", merged, e)
+            ),
+            Err(param) => log::error!("override for {:?} should have been rejected", param),
+        }
+    }
+    if let Some(selection) = overrides.voice.as_ref() {
+        if let Err(e) = engine.set_voice(Some(selection)) {
+            log::warn!("couldn't select voice {:?} for this utterance: {}", selection, e);
+            notify(state, TtsBeNotice::Error { code: e.code(), message: e.message() });
+        }
+    }
+    // punctuation isn't one of the client's settings, so it's always reset to espeak's default
+    let punctuation = match overrides.punctuation {
+        None | Some(TtsPunctuation::None) => 0,
+        Some(TtsPunctuation::All) => 1,
+    };
+    EspeakError::check(unsafe { espeak_ng_SetParameter(espeakPUNCTUATION, punctuation, 0) }).unwrap_or_else(
+        |e| log::error!("couldn't set punctuation mode: {}", e)
+    );
+}
+
+/// Converts an espeak text position (in characters, counting from 1) into a byte offset into `text`.
+fn char_offset(text: &str, position: i32) -> usize {
+    let index = (position.max(1) - 1) as usize;
+    text.char_indices().nth(index).map_or(text.len(), |(offset, _)| offset)
+}
+
+/// Puts the session's settings into the engine ahead of an utterance or query, undoing any
+/// overrides left over from the last utterance. Anything flagged by `PARAMS_CHANGED` up to this
+/// point is picked up here, so the flag is cleared first. A `session` of `None` gets the defaults.
+fn engine_settings(engine: &mut Engine, state: &mut SynthState, sessions: &Mutex<Sessions>, session: Option<PID>) -> SpeechParams {
+    PARAMS_CHANGED.store(false, Ordering::SeqCst);
+    let params = sessions.lock().unwrap().params(session);
+    engine.set_latency(params.latency).unwrap_or_else(
+        |e| log::error!("couldn't switch to {:?}: {}", params.latency, e)
+    );
+    apply_settings(engine, state, &params, sessions, session);
+    params
+}
+
+/// Installs the session's callback. A `notice_op` of `None` keeps the notice opcode from an
+/// earlier registration by the same server, so re-registering to change the rate doesn't drop it.
+/// If the client's server can't be connected to, the session keeps the callback it had.
+fn register_callback(session: &mut Session, sid: [u32; 4], op: u32, samples_per_cb: Option<u32>, notice_op: Option<u32>) {
+    let sid = SID::from_array(sid);
+    let cid = match xous::connect(sid) {
+        Ok(cid) => cid,
+        Err(e) => {
+            log::error!("couldn't connect to the client's callback server: {:?}", e);
+            return;
+        }
+    };
+    let notice_op = notice_op.or(session.callback.filter(|cb| cb.sid == sid).and_then(|cb| cb.notice_op));
+    session.callback = Some(Callback {
+        sid,
+        cid,
+        op,
+        samples_per_cb,
+        notice_op,
+    });
+}
+
+/// Points the audio callback at `callback` for the next utterance. Nothing is held back for a
+/// `samples_per_cb` block at this point: an utterance that ends flushes it, and one that is
+/// preempted or paused takes it along in its remainder.
+fn switch_callback(state: &mut SynthState, callback: Option<Callback>, session: Option<PID>) {
+    state.cb = callback;
+    state.session = session;
+}
+
+/// Forgets a session: its queued speech is dropped, along with its settings, and its callback
+/// connection is closed. Only the synth thread may call this, since it's the one that sends on
+/// callback connections.
+fn drop_session(pid: PID, state: &mut SynthState, sessions: &Mutex<Sessions>, queue: &Mutex<SpeechQueue>) {
+    let flushed = queue.lock().unwrap().flush_all(Some(pid));
+    abandon(flushed);
+    let removed = sessions.lock().unwrap().remove(pid);
+    if let Some(cb) = removed.and_then(|session| session.callback) {
+        if state.session == Some(pid) {
+            state.pending.clear();
+            state.cb = None;
+            state.session = None;
+        }
+        unsafe { xous::disconnect(cb.cid) }.unwrap_or_else(
+            |e| log::warn!("couldn't disconnect from {:?}: {:?}", pid, e)
+        );
+    }
+    log::info!("session {:?} closed", pid);
+}
+
+/// Drops every session whose callback turned out to be unreachable. Returns true if the session of
+/// the utterance being spoken was one of them.
+fn drop_unreachable(state: &mut SynthState, sessions: &Mutex<Sessions>, queue: &Mutex<SpeechQueue>) -> bool {
+    let current_lost = state.cb_lost();
+    for pid in std::mem::take(&mut state.unreachable) {
+        drop_session(pid, state, sessions, queue);
+    }
+    current_lost
+}
+
+/// Aborts the utterance the synth thread is currently speaking and flushes everything queued behind
+/// it, but only where that is at `priority` and, if `scope` is given, belongs to that session.
+/// Other priorities are left to preemption.
+fn interrupt(priority: TtsPriority, scope: Option<PID>, queue: &Mutex<SpeechQueue>, synth_cid: CID, handshake: &mut AbortHandshake) {
+    let (flushed, aborting) = {
+        let mut queue = queue.lock().unwrap();
+        let flushed = queue.flush(priority, scope);
+        let in_scope = |current: &CurrentUtterance| scope.is_none_or(|s| current.session == Some(s));
+        let aborting = match queue.current() {
+            // set under the lock, so the flag can only ever hit the utterance we looked at. If it was
+            // already set, an earlier abort of this utterance timed out, and there's no point waiting again.
+            Some(current) if current.priority == priority && in_scope(&current)
+                && !TTS_SHOULD_ABORT.swap(true, Ordering::SeqCst) =>
+                Some((current.id, std::time::Instant::now())),
+            _ => None,
+        };
+        (flushed, aborting)
+    };
+    cut_off(&flushed, synth_cid);
+    abandon(flushed);
+    if let Some((id, requested)) = aborting {
+        handshake.wait(id, requested);
+    }
+}
+
+/// Silences the engine: aborts the current utterance and drops everything queued or paused, at
+/// every priority. With a `scope`, only that session's speech is silenced and only its pause is
+/// lifted. Returns the ID of the utterance that was cut off and how many characters of it had been
+/// spoken: the one that was playing, or else the first paused or preempted one that was dropped.
+fn stop(scope: Option<PID>, queue: &Mutex<SpeechQueue>, synth_cid: CID, handshake: &mut AbortHandshake) -> Option<(u32, u32)> {
+    let (flushed, current, already_aborting) = {
+        let mut queue = queue.lock().unwrap();
+        let flushed = queue.flush_all(scope);
+        queue.resume(scope);
+        if scope.is_none() {
+            PAUSE_REQUESTED.store(false, Ordering::SeqCst);
+            PREEMPT_REQUESTED.store(false, Ordering::SeqCst);
+        }
+        let current = queue.current()
+            .filter(|c| scope.is_none_or(|s| c.session == Some(s)))
+            .map(|c| c.id);
+        let already_aborting = current.is_some() && TTS_SHOULD_ABORT.swap(true, Ordering::SeqCst);
+        (flushed, current, already_aborting)
+    };
+    let requested = std::time::Instant::now();
+    let held = flushed.iter().find(|u| u.start > 0).map(|u| (u.id, u.start));
+    cut_off(&flushed, synth_cid);
+    abandon(flushed);
+    match current {
+        Some(id) => {
+            if !already_aborting {
+                handshake.wait(id, requested);
+            }
+            Some((id, SPOKEN_TO.load(Ordering::SeqCst)))
+        }
+        None => held,
+    }
+}
+
+/// Length of `samples` of audio at the engine's sample rate, in ms.
+fn duration_ms(samples: u32) -> u32 {
+    let sample_rate = unsafe { espeak_ng_GetSampleRate() }.max(1);
+    (samples as u64 * 1000 / sample_rate as u64) as u32
+}
+
+/// Turns away a request that can't be served as it was sent. A mutable lend goes back with no
+/// valid data, so the client's decode fails instead of reading its own request back as an answer,
+/// and a blocking scalar gets a reply of 0 so the caller isn't left waiting. Anything else has no
+/// way back to the client.
+fn refuse(msg: &mut xous::MessageEnvelope) {
+    if let Some(mem) = msg.body.memory_message_mut() {
+        mem.offset = None;
+        mem.valid = None;
+    } else if let xous::Message::BlockingScalar(_) = msg.body {
+        xous::return_scalar(msg.sender, 0).unwrap_or_else(
+            |e| log::warn!("couldn't refuse request: {:?}", e)
+        );
+    }
+}
+
+/// Fills in the `TtsStrToWav` that a `StrToWavSync` caller lent us, and lets the caller go.
+fn complete_sync(mut reply: xous::MessageEnvelope, update: impl FnOnce(&mut TtsStrToWav)) {
+    if let Some(mem) = reply.body.memory_message_mut() {
+        match decode::<TtsStrToWav>(mem) {
+            Ok(mut request) => {
+                update(&mut request);
+                encode(mem, &request).unwrap_or_else(
+                    |e| log::error!("couldn't return utterance outcome: {}", e)
+                );
+            }
+            Err(e) => {
+                log::error!("couldn't decode the waiting utterance request: {}", e);
+                refuse(&mut reply);
+            }
+        }
+    }
+}
+
+/// Lets go of utterances that were flushed from the queue, answering any `StrToWavSync` callers.
+/// Those that had started playing, before a pause or a preemption, were cut off just like the
+/// current utterance; the queue keeps note of them for `report_cut_off`.
+fn abandon(utterances: Vec<Utterance>) {
+    if !utterances.is_empty() {
+        log::info!("flushed {} queued utterances", utterances.len());
+    }
+    for utterance in utterances {
+        if let Some(reply) = utterance.reply {
+            let outcome = TtsOutcome {
+                result: TtsResult::Aborted,
+                samples: utterance.samples,
+                duration_ms: duration_ms(utterance.samples),
+            };
+            complete_sync(reply, |request| request.outcome = Some(outcome));
+        }
+    }
+}
+
+/// Wakes up the synth thread to tell the clients of any started utterances among `flushed` that
+/// they were cut off, since it's the only one that sends on callback connections.
+fn cut_off(flushed: &[Utterance], synth_cid: CID) {
+    if flushed.iter().any(|u| u.start > 0) {
+        notify_synth(synth_cid, SynthOp::CutOff, 0);
+    }
+}
+
+/// Sends a `TtsBeNotice::Aborted` for every started utterance that was flushed from the queue.
+/// Only a notice, since the audio stream can't tell which utterance an `Abort` is meant for, and by
+/// now it may be carrying another one of the same session.
+fn report_cut_off(state: &mut SynthState, sessions: &Mutex<Sessions>, queue: &Mutex<SpeechQueue>) {
+    let cut_off = queue.lock().unwrap().take_cut_off();
+    for (pid, id, offset) in cut_off {
+        let Some(cb) = sessions.lock().unwrap().callback(pid) else {
+            continue;
+        };
+        if let Err(e) = send_notice(&cb, TtsBeNotice::Aborted { id, offset }) {
+            state.lost(Some(pid), e);
+        }
+    }
+}
+
+/// Queues `utterance` and wakes up the synth thread if it's idle, preempting the current utterance
+/// if the new one outranks it. With `drop_if_busy` the utterance is only accepted if nothing of
+/// the same or a higher priority is playing or queued, and with an `Exclusive` policy only if no
+/// other session is speaking; if it isn't accepted, it is handed back.
+fn enqueue(
+    utterance: Utterance,
+    drop_if_busy: bool,
+    policy: MixingPolicy,
+    queue: &Mutex<SpeechQueue>,
+    sessions: &Mutex<Sessions>,
+    synth_cid: CID,
+) -> Result<(), Box<Utterance>> {
+    if utterance.session.and_then(|pid| sessions.lock().unwrap().callback(pid)).is_none() {
+        log::warn!("no callback registered, dropping utterance");
+        return Err(Box::new(utterance));
+    }
+    if ENGINE_HUNG.load(Ordering::SeqCst) {
+        log::warn!("the engine is hung, dropping utterance");
+        return Err(Box::new(utterance));
+    }
+    // the synth thread only clears TTS_RUNNING with the queue locked and nothing ready, so holding the
+    // lock here means either it will find this utterance, or we are the ones to wake it up
+    let mut queue = queue.lock().unwrap();
+    if policy == MixingPolicy::Exclusive && queue.busy_for_others(utterance.session) {
+        log::debug!("another session is speaking, dropping utterance");
+        return Err(Box::new(utterance));
+    }
+    if drop_if_busy && queue.busy_at(utterance.priority) {
+        log::debug!("engine busy, dropping utterance");
+        return Err(Box::new(utterance));
+    }
+    if queue.current().is_some_and(|current| current.priority < utterance.priority) {
+        log::debug!("{:?} utterance preempts the current one", utterance.priority);
+        PREEMPT_REQUESTED.store(true, Ordering::SeqCst);
+    }
+    queue.push(utterance);
+    if TTS_RUNNING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+        notify_synth(synth_cid, SynthOp::NewString, 0);
+    }
+    Ok(())
+}
+
+/// Speaks one utterance from start to finish. Runs on the synth thread. If the utterance is
+/// preempted or paused, returns whatever is left of it, starting from the word it was cut off at.
+fn synthesize_utterance(engine: &mut Engine, state: &mut SynthState, sessions: &Mutex<Sessions>, utterance: Utterance) -> Option<Utterance> {
+    let Utterance { id, session, text, overrides, priority, start, samples, pending, mut reply } = utterance;
+    log::trace!("espeak synth #{}: {}", id, &text);
+    SPOKEN_TO.store(start, Ordering::SeqCst);
+    state.aborted = false;
+    state.delivered = samples;
+    state.pending = pending;
+    let current_params = engine_settings(engine, state, sessions, session);
+    apply_overrides(engine, state, &current_params, &overrides);
+    // synthesis runs in segments: if the client changes its settings, the callback
+    // stops at the next sentence and we pick up from there with the new settings
+    let mut segment_start = 0;
+    let mut remainder = None;
+    let mut failure = None;
+    let mut stalled = false;
+    loop {
+        let segment = &text[segment_start..];
+        state.segment_samples = 0;
+        state.restart_at = None;
+        state.segment_base = start + text[..segment_start].chars().count() as u32;
+        *LAST_PROGRESS.lock().unwrap() = Some(Instant::now());
+        let result = engine.synthesize(segment, state as *mut SynthState as *mut c_void);
+        *LAST_PROGRESS.lock().unwrap() = None;
+        if WATCHDOG_TRIPPED.load(Ordering::SeqCst) {
+            stalled = true;
+            break;
+        }
+        match result {
+            // SpeechStopped is how our callback ends every utterance, it's not a failure
+            Ok(()) | Err(EspeakError::SpeechStopped) => (),
+            Err(e) => {
+                failure = Some(e.code());
+                report_error(state, e);
+                break;
+            }
+        }
+        match state.restart_at.take() {
+            Some(position) => {
+                segment_start += char_offset(segment, position);
+                if PREEMPT_REQUESTED.load(Ordering::SeqCst) || PAUSE_REQUESTED.load(Ordering::SeqCst) {
+                    log::debug!("stopped at byte {}", segment_start);
+                    remainder = Some(Utterance {
+                        id,
+                        session,
+                        text: text[segment_start..].to_string(),
+                        overrides,
+                        priority,
+                        start: start + text[..segment_start].chars().count() as u32,
+                        samples: state.delivered,
+                        // the partial block goes out with the rest of this utterance, not glued to whatever preempted it
+                        pending: std::mem::take(&mut state.pending),
+                        reply: reply.take(),
+                    });
+                    break;
+                }
+                PARAMS_CHANGED.store(false, Ordering::SeqCst);
+                let current_params = sessions.lock().unwrap().params(session);
+                log::debug!("applying new settings at byte {}", segment_start);
+                apply_settings(engine, state, &current_params, sessions, session);
+                apply_overrides(engine, state, &current_params, &overrides);
+            }
+            None => break,
+        }
+    }
+    if state.aborted {
+        let offset = SPOKEN_TO.load(Ordering::SeqCst);
+        log::info!("utterance #{} aborted at character {}", id, offset);
+        notify(state, TtsBeNotice::Aborted { id, offset });
+    }
+    if stalled {
+        // if the watchdog already declared the engine hung, it has told the client as well
+        if !ENGINE_HUNG.swap(false, Ordering::SeqCst) {
+            let (stalled_ms, count) = (STALLED_MS.load(Ordering::SeqCst), WATCHDOG_EVENTS.load(Ordering::SeqCst));
+            send_audio(state, &[], Some(TtsBeControl::Abort));
+            notify(state, TtsBeNotice::Stalled { id, stalled_ms, count });
+        } else {
+            log::warn!("espeak came back after all");
+        }
+        log::warn!("rebuilding the engine after #{} stalled", id);
+        state.pending.clear();
+        engine.reset(sessions.lock().unwrap().params(session).words_per_minute);
+        engine_settings(engine, state, sessions, session);
+        WATCHDOG_TRIPPED.store(false, Ordering::SeqCst);
+    } else {
+        log::trace!("espeak sync");
+        engine.sync();
+        log::debug!("espeak done");
+        if engine.trim(sessions.lock().unwrap().params(session).words_per_minute) {
+            engine_settings(engine, state, sessions, session);
+        }
+    }
+    if let Some(reply) = reply {
+        let samples = state.delivered;
+        let result = match failure {
+            None if stalled => TtsResult::Stalled,
+            Some(code) => TtsResult::Error(code),
+            None if state.aborted => TtsResult::Aborted,
+            None => TtsResult::Completed,
+        };
+        complete_sync(reply, |request| request.outcome = Some(TtsOutcome { result, samples, duration_ms: duration_ms(samples) }));
+    }
+    remainder
+}
+
+/// Runs on its own thread, and gives up on the current utterance if the engine goes `timeout`
+/// without delivering any audio. That stops synthesis if espeak is still calling back (e.g. looping
+/// over empty chunks), and the synth thread then tells the client and rebuilds the engine.
+///
+/// If espeak doesn't return within another `timeout`, it is stuck for good: nothing in this
+/// process can get the synth thread back. The engine is declared hung, everything queued is
+/// dropped, new utterances are turned away and the client of the stuck one is told here, since
+/// the synth thread can't. Stop and the abort handshake time out instead of hanging the server,
+/// and it takes a restart of the server to speak again.
+fn watchdog(timeout: Duration, queue: &Mutex<SpeechQueue>, sessions: &Mutex<Sessions>) {
+    loop {
+        std::thread::sleep(timeout / 4);
+        let (stalled_for, hung) = {
+            let last_progress = LAST_PROGRESS.lock().unwrap();
+            match *last_progress {
+                // decided with the lock held, so the synth thread can't finish in between
+                Some(last) if last.elapsed() >= timeout && !WATCHDOG_TRIPPED.swap(true, Ordering::SeqCst) =>
+                    (last.elapsed(), false),
+                Some(last) if last.elapsed() >= timeout * 2 && !ENGINE_HUNG.swap(true, Ordering::SeqCst) =>
+                    (last.elapsed(), true),
+                _ => continue,
+            }
+        };
+        let current = queue.lock().unwrap().current();
+        let id = current.map_or(0, |c| c.id);
+        if !hung {
+            let count = WATCHDOG_EVENTS.fetch_add(1, Ordering::SeqCst) + 1;
+            STALLED_MS.store(stalled_for.as_millis() as u32, Ordering::SeqCst);
+            log::error!("espeak made no progress on #{} for {}ms, giving up on it ({} stalls so far)",
+                id, stalled_for.as_millis(), count);
+            continue;
+        }
+        log::error!("espeak hasn't returned from #{} for {}ms, it's hung; restart the server to speak again",
+            id, stalled_for.as_millis());
+        // clients with a started utterance among these hear about it if espeak ever comes back
+        let flushed = queue.lock().unwrap().flush_all(None);
+        abandon(flushed);
+        // the synth thread is stuck inside espeak, so nothing else is sending on this connection
+        if let Some(cb) = current.and_then(|c| c.session).and_then(|pid| sessions.lock().unwrap().callback(pid)) {
+            let count = WATCHDOG_EVENTS.load(Ordering::SeqCst);
+            send_block(&cb, &[], Some(TtsBeControl::Abort))
+                .and_then(|()| send_notice(&cb, TtsBeNotice::Stalled { id, stalled_ms: stalled_for.as_millis() as u32, count }))
+                .unwrap_or_else(|e| log::warn!("couldn't tell the client about the hung engine: {:?}", e));
+        }
+    }
 }
 
-#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+/// Wakes the synth thread up with `op`. This only fails if the thread has died, which no client
+/// request can repair, so it's logged rather than taking the server down too.
+fn notify_synth(synth_cid: CID, op: SynthOp, arg: usize) {
+    send_message(synth_cid, Message::new_scalar(op.to_usize().unwrap(), arg, 0, 0, 0))
+        .map(|_| ()).unwrap_or_else(|e| log::error!("couldn't send {:?} to the synth thread: {:?}", op, e));
+}
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone)]
 pub enum SynthOp {
-    /// New string for synthesis
+    /// New string(s) for synthesis are waiting in the queue
     NewString,
-    /// Exit server
+    /// A client request that needs the engine but produces no audio (phonemes, voices);
+    /// the envelope is waiting in the request channel
+    Request,
+    /// Close the session of the PID in `arg1`
+    Unregister,
+    /// Utterances that had started playing were flushed from the queue; tell their sessions
+    CutOff,
+    /// Shut the engine down and exit the thread
     Quit,
 }
 
@@ -117,48 +881,178 @@ fn main() -> ! {
     // put the synthesizer in its own thread
     let synth_sid = xous::create_server().unwrap();
     let synth_cid = xous::connect(synth_sid).unwrap();
-    let synth_string = Arc::new(Mutex::new(String::new()));
-    let words_per_minute = Arc::new(AtomicI32::new(175));
-    std::thread::spawn({
-        let synth_string = synth_string.clone();
-        let words_per_minute = words_per_minute.clone();
+    let queue = Arc::new(Mutex::new(SpeechQueue::default()));
+    let sessions = Arc::new(Mutex::new(Sessions::default()));
+    // engine queries are handed over as the whole envelope, so the client's buffer is only
+    // returned once the synth thread has written the result into it
+    let (request_tx, request_rx) = std::sync::mpsc::channel::<xous::MessageEnvelope>();
+    let (abort_tx, abort_rx) = std::sync::mpsc::channel::<u32>();
+    // never sent on; it disconnects when the synth thread exits, so Quit can wait with a timeout
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    let engine_config = EngineConfig::default();
+    let mut handshake = AbortHandshake::new(abort_rx, engine_config.abort_timeout);
+    let policy = engine_config.mixing;
+    let access = engine_config.access;
+    let synth_thread = std::thread::spawn({
+        let queue = queue.clone();
+        let sessions = sessions.clone();
         move || {
+            let _done = done_tx;
+            let synth_cid = xous::connect(synth_sid).unwrap();
+            let mut engine = Engine::new(tts_cb, engine_config, SpeechParams::default().words_per_minute);
+            let mut state = SynthState::default();
             loop {
                 let msg = xous::receive_message(synth_sid).unwrap();
                 match FromPrimitive::from_usize(msg.body.id()) {
                     Some(SynthOp::NewString) => {
-                        if unsafe{CB.is_some()} {
-                            // ASSUME: the caller set the TTS_RUNNING lock before making the call
-                            let text = synth_string.lock().unwrap().clone();
-                            let msg_len = text.len();
-                            log::trace!("espeak synth: {}", &text);
-                            let cstr = std::ffi::CString::new(text).expect("couldn't convert String to Cstring");
-                            unsafe {
-                                espeak_ffi_setup(
-                                    tts_cb,
-                                    words_per_minute.load(Ordering::SeqCst),
-                                )
-                            };
-                            log::trace!("espeak sample rate: {}", unsafe {espeak_ng_GetSampleRate()});
-                            unsafe {
-                                espeak_ffi_synth(
-                                cstr.as_ptr() as *const i8,
-                                msg_len as c_uint,
-                                ::core::ptr::null::<c_void>() as *mut c_void,
-                            );}
-                            log::trace!("espeak sync");
-                            unsafe {
-                                espeak_ffi_sync();
-                                espeak_ng_Terminate();
+                        report_cut_off(&mut state, &sessions, &queue);
+                        // ASSUME: the caller set TTS_RUNNING before making the call
+                        let next = {
+                            let mut queue = queue.lock().unwrap();
+                            // whatever asked for a preemption is at the head of the queue now
+                            PREEMPT_REQUESTED.store(false, Ordering::SeqCst);
+                            queue.pop()
+                        };
+                        let remainder = next.and_then(|utterance| {
+                            let callback = utterance.session.and_then(|pid| sessions.lock().unwrap().callback(pid));
+                            if callback.is_none() {
+                                log::warn!("#{} has no callback to go to any more, dropping it", utterance.id);
+                                abandon(vec![utterance]);
+                                return None;
+                            }
+                            switch_callback(&mut state, callback, utterance.session);
+                            let remainder = synthesize_utterance(&mut engine, &mut state, &sessions, utterance);
+                            // what's left of an utterance nobody is listening to is dropped along with its session
+                            remainder.filter(|_| !drop_unreachable(&mut state, &sessions, &queue))
+                        });
+                        // one utterance per message, so engine queries can get in between
+                        let mut queue = queue.lock().unwrap();
+                        let finished = queue.finish();
+                        // an abort or pause only ever applies to the utterance that was current when it was requested
+                        if TTS_SHOULD_ABORT.swap(false, Ordering::SeqCst) {
+                            if let Some(finished) = finished {
+                                abort_tx.send(finished.id).ok();
                             }
-                            log::debug!("espeak done");
-                            // espeak leaks memory. You need to do this or else we run out of space.
-                            reset_heap();
+                        }
+                        PAUSE_REQUESTED.store(false, Ordering::SeqCst);
+                        if let Some(remainder) = remainder {
+                            queue.push_front(remainder);
+                        }
+                        if !queue.has_ready() {
                             TTS_RUNNING.store(false, Ordering::SeqCst);
+                        } else {
+                            notify_synth(synth_cid, SynthOp::NewString, 0);
+                        }
+                    }
+                    Some(SynthOp::Request) => {
+                        while let Ok(mut env) = request_rx.try_recv() {
+                            let opcode: Option<Opcode> = FromPrimitive::from_usize(env.body.id());
+                            let session = env.sender.pid();
+                            let Some(mem) = env.body.memory_message_mut() else {
+                                continue;
+                            };
+                            engine_settings(&mut engine, &mut state, &sessions, session);
+                            match opcode {
+                                Some(Opcode::TextToPhonemes) => {
+                                    match decode::<TtsPhonemes>(mem) {
+                                        Ok(mut request) => {
+                                            match text_to_phonemes(&request.text, request.format) {
+                                                Ok(phonemes) => request.phonemes = phonemes,
+                                                Err(e) => {
+                                                    log::warn!("phoneme transcription failed: {}", e);
+                                                    request.error = Some(e.code());
+                                                }
+                                            }
+                                            match encode(mem, &request) {
+                                                Ok(()) => (),
+                                                Err(e @ ReplyError::TooLarge { .. }) => {
+                                                    // the transcription is usually longer than the text, so it may
+                                                    // not fit where the text did; E2BIG fits anywhere
+                                                    log::warn!("couldn't return phonemes: {}", e);
+                                                    request.text.clear();
+                                                    request.phonemes.clear();
+                                                    request.error = Some(EspeakError::Errno(7).code());
+                                                    encode(mem, &request).unwrap_or_else(
+                                                        |e| log::error!("couldn't return phoneme error: {}", e)
+                                                    );
+                                                }
+                                                Err(e) => log::error!("couldn't return phonemes: {}", e),
+                                            }
+                                        }
+                                        Err(e) => {
+                                            log::error!("couldn't decode phoneme request: {}", e);
+                                            refuse(&mut env);
+                                        }
+                                    }
+                                }
+                                Some(Opcode::ListVoices) => {
+                                    match decode::<TtsVoiceList>(mem) {
+                                        Ok(mut request) => {
+                                            let voices = voices::list_voices();
+                                            request.total = voices.len() as u32;
+                                            request.voices = voices.into_iter()
+                                                .skip(request.start as usize)
+                                                .take(VOICE_PAGE_LEN)
+                                                .collect();
+                                            encode(mem, &request).unwrap_or_else(
+                                                |e| log::error!("couldn't return voice list: {}", e)
+                                            );
+                                        }
+                                        Err(e) => {
+                                            log::error!("couldn't decode voice list request: {}", e);
+                                            refuse(&mut env);
+                                        }
+                                    }
+                                }
+                                Some(Opcode::SetVoice) => {
+                                    match decode::<TtsSetVoice>(mem) {
+                                        Ok(mut request) => {
+                                            match engine.set_voice(Some(&request.voice)) {
+                                                Ok(()) => {
+                                                    log::info!("voice set to {:?}", request.voice);
+                                                    if let Some(pid) = session {
+                                                        sessions.lock().unwrap().entry(pid).voice = Some(request.voice.clone());
+                                                    }
+                                                }
+                                                Err(e) => {
+                                                    log::warn!("couldn't select voice {:?}: {}", request.voice, e);
+                                                    request.error = Some(e.code());
+                                                }
+                                            }
+                                            encode(mem, &request).unwrap_or_else(
+                                                |e| log::error!("couldn't return voice selection result: {}", e)
+                                            );
+                                        }
+                                        Err(e) => {
+                                            log::error!("couldn't decode voice selection: {}", e);
+                                            refuse(&mut env);
+                                        }
+                                    }
+                                }
+                                _ => log::error!("synth thread got an unexpected request: {:?}", opcode),
+                            }
+                            if engine.trim(sessions.lock().unwrap().params(session).words_per_minute) {
+                                engine_settings(&mut engine, &mut state, &sessions, session);
+                            }
+                        }
+                        drop_unreachable(&mut state, &sessions, &queue);
+                    }
+                    Some(SynthOp::CutOff) => {
+                        report_cut_off(&mut state, &sessions, &queue);
+                        drop_unreachable(&mut state, &sessions, &queue);
+                    }
+                    Some(SynthOp::Unregister) => {
+                        match msg.body.scalar_message().and_then(|s| PID::new(s.arg1 as u8)) {
+                            Some(pid) => drop_session(pid, &mut state, &sessions, &queue),
+                            None => log::error!("unregister without a PID: {:?}", msg),
                         }
                     }
                     Some(SynthOp::Quit) => {
-                        xous::return_scalar(msg.sender, 1).unwrap();
+                        // any engine queries still waiting are refused, so they don't read as answered
+                        while let Ok(mut env) = request_rx.try_recv() {
+                            refuse(&mut env);
+                        }
+                        engine.shutdown();
                         break;
                     }
                     None => log::warn!("couldn't interpret opcode: {:?}", msg),
@@ -168,65 +1062,400 @@ fn main() -> ! {
         }
     });
 
+    std::thread::spawn({
+        let queue = queue.clone();
+        let sessions = sessions.clone();
+        move || watchdog(engine_config.watchdog_timeout, &queue, &sessions)
+    });
+
     loop {
-        let msg = xous::receive_message(sid).unwrap();
+        let mut msg = xous::receive_message(sid).unwrap();
+        // every request is attributed to the session of the process that sent it
+        let Some(session) = msg.sender.pid() else {
+            log::error!("message without a sender PID: {:?}", msg);
+            continue;
+        };
         match FromPrimitive::from_usize(msg.body.id()) {
-            Some(TtsBeOpcode::StrToWav) => {
-                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
-                let msg = buffer.to_original::<TtsBackendMsg, _>().unwrap();
+            // after a failed Negotiate, the client's layout of these may not be ours, so they'd be misread
+            Some(Opcode::TextToPhonemes | Opcode::ListVoices | Opcode::SetVoice | Opcode::SetParams
+                | Opcode::StrToWavExt | Opcode::StrToWavSync)
+                if sessions.lock().unwrap().negotiation(session).is_some_and(|n| n != TtsNegotiation::Accepted) => {
+                log::warn!("{:?} failed to negotiate a protocol revision, refusing opcode {}", session, msg.body.id());
+                refuse(&mut msg);
+            },
+            Some(Opcode::StrToWav) => {
+                let Some(mem) = msg.body.memory_message() else {
+                    log::error!("StrToWav needs a memory message");
+                    refuse(&mut msg);
+                    continue;
+                };
+                let msg = match decode::<TtsBackendMsg>(mem) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        log::error!("couldn't decode string to speak: {}", e);
+                        continue;
+                    }
+                };
+                if !sessions.lock().unwrap().permits(access, session) {
+                    // there's no reply to a plain StrToWav, so the text is just dropped
+                    log::warn!("{:?} isn't allowed to speak, dropping its text", session);
+                    continue;
+                }
                 log::debug!("outer processing for string {}", msg.text.as_str());
-                if unsafe{CB.is_some()} {
-                    // if the synthesizer is running, indicate it should abort, then wait until the abortion is confirmed via
-                    // the TTS_RUNNING state changing to false
-                    if TTS_RUNNING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
-                        // we weren't able to get the lock. abort synthesis, until we can get the lock
-                        let mut timeout = 0;
-                        TTS_SHOULD_ABORT.store(true, Ordering::SeqCst);
-                        loop {
-                            xous::yield_slice(); // we don't have a ticktimer in the FFI land, so a busy-wait is the best we can do until we get a condvar in `libstd`
-                            if TTS_RUNNING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
-                                break;
+                let id = queue.lock().unwrap().next_id();
+                interrupt(TtsPriority::default(), policy.scope(session), &queue, synth_cid, &mut handshake);
+                let utterance = Utterance { id, session: Some(session), text: String::from(msg.text.as_str()), ..Default::default() };
+                enqueue(utterance, false, policy, &queue, &sessions, synth_cid).ok();
+            },
+            Some(Opcode::StrToWavExt) | Some(Opcode::StrToWavSync) => {
+                let sync = msg.body.id() == Opcode::StrToWavSync.to_usize().unwrap();
+                let Some(mem) = msg.body.memory_message_mut() else {
+                    log::error!("StrToWavExt and StrToWavSync need a memory message");
+                    refuse(&mut msg);
+                    continue;
+                };
+                let mut request = match decode::<TtsStrToWav>(mem) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        log::error!("couldn't decode utterance: {}", e);
+                        refuse(&mut msg);
+                        continue;
+                    }
+                };
+                if !sessions.lock().unwrap().permits(access, session) {
+                    log::warn!("{:?} isn't allowed to speak, denying its text", session);
+                    request.denied = true;
+                    encode(mem, &request).unwrap_or_else(
+                        |e| log::error!("couldn't return utterance result: {}", e)
+                    );
+                    continue;
+                }
+                log::debug!("outer processing for string {} with {:?}", request.text, request.overrides);
+                let checked = sessions.lock().unwrap().params(Some(session)).merged(&request.overrides.prosody);
+                match checked {
+                    Ok(_) => {
+                        request.id = queue.lock().unwrap().next_id();
+                        let mut utterance = Utterance {
+                            id: request.id,
+                            session: Some(session),
+                            text: request.text.clone(),
+                            overrides: request.overrides.clone(),
+                            priority: request.priority,
+                            ..Default::default()
+                        };
+                        let mode = request.mode;
+                        if sync {
+                            // the caller stays blocked until the synth thread fills in the outcome
+                            encode(mem, &request).unwrap_or_else(
+                                |e| log::error!("couldn't return utterance result: {}", e)
+                            );
+                            if mode == TtsQueueMode::Interrupt {
+                                interrupt(utterance.priority, policy.scope(session), &queue, synth_cid, &mut handshake);
                             }
-                            xous::yield_slice(); // aggressively yield our time
-                            timeout += 1;
-                            if timeout > 100 { // this is about 1-2 seconds timeout
-                                log::warn!("timeout waiting for synthesis to abort");
-                                break;
+                            utterance.reply = Some(msg);
+                            if let Err(dropped) = enqueue(utterance, mode == TtsQueueMode::DropIfBusy, policy, &queue, &sessions, synth_cid) {
+                                if let Some(reply) = dropped.reply {
+                                    complete_sync(reply, |request| request.dropped = true);
+                                }
                             }
+                        } else if mode == TtsQueueMode::Interrupt {
+                            // answer first, so the client isn't held up by the abort handshake
+                            encode(mem, &request).unwrap_or_else(
+                                |e| log::error!("couldn't return utterance result: {}", e)
+                            );
+                            drop(msg);
+                            interrupt(utterance.priority, policy.scope(session), &queue, synth_cid, &mut handshake);
+                            enqueue(utterance, false, policy, &queue, &sessions, synth_cid).ok();
+                        } else {
+                            request.dropped = enqueue(utterance, mode == TtsQueueMode::DropIfBusy, policy, &queue, &sessions, synth_cid).is_err();
+                            encode(mem, &request).unwrap_or_else(
+                                |e| log::error!("couldn't return utterance result: {}", e)
+                            );
                         }
-                        TTS_SHOULD_ABORT.store(false, Ordering::SeqCst);
-                        log::info!("abort processed");
-                    }
-                    // at this point TTS_RUNNING must be true, so we're clear to change the state variables
-                    synth_string.lock().unwrap().clear();
-                    synth_string.lock().unwrap().push_str(msg.text.as_str());
-                    send_message(synth_cid,
-                        Message::new_scalar(SynthOp::NewString.to_usize().unwrap(), 0, 0, 0, 0)
-                    ).expect("couldn't kick off a new string to the synth thread");
-                }
-            },
-            Some(TtsBeOpcode::RegisterCb) => {
-                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
-                let config = buffer.to_original::<TtsBackendConfig, _>().unwrap();
-                unsafe {
-                    CB = Some(Callback {
-                        _sid: SID::from_array(config.sid),
-                        cid: xous::connect(SID::from_array(config.sid)).unwrap(),
-                        op: config.op,
-                        samples_per_cb: config.samples_per_cb,
-                    });
+                    }
+                    Err(param) => {
+                        log::warn!("rejecting utterance, {:?} is outside of {:?}", param, param.legal_range());
+                        request.rejected = Some(param);
+                        encode(mem, &request).unwrap_or_else(
+                            |e| log::error!("couldn't return utterance result: {}", e)
+                        );
+                    }
+                }
+            },
+            // the plain registration has the upstream layout, so only a failed Negotiate rules it out
+            Some(Opcode::RegisterCb)
+                if sessions.lock().unwrap().negotiation(session).is_some_and(|n| n != TtsNegotiation::Accepted) => {
+                log::warn!("{:?} failed to negotiate a protocol revision, ignoring its registration", session);
+            },
+            Some(Opcode::RegisterCb) => {
+                let Some(mem) = msg.body.memory_message() else {
+                    log::error!("RegisterCb needs a memory message");
+                    refuse(&mut msg);
+                    continue;
+                };
+                let config = match decode::<TtsBackendConfig>(mem) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        log::error!("couldn't decode registration: {}", e);
+                        continue;
+                    }
+                };
+                let mut sessions = sessions.lock().unwrap();
+                if !sessions.admit(access, session) {
+                    log::warn!("{:?} isn't allowed to register, ignoring", session);
+                    continue;
+                }
+                let session = sessions.entry(session);
+                register_callback(session, config.sid, config.op, config.samples_per_cb, None);
+                let prosody = TtsProsody { words_per_minute: config.words_per_minute, ..Default::default() };
+                match session.params.merged(&prosody) {
+                    Ok(merged) => session.params = merged,
+                    // there's no way to reply to a plain RegisterCb, so the best we can do is keep the old rate
+                    Err(_) => log::warn!("ignoring out of range rate {:?}", config.words_per_minute),
+                }
+            },
+            Some(Opcode::RegisterCbExt) if sessions.lock().unwrap().negotiation(session) != Some(TtsNegotiation::Accepted) => {
+                // the layout may not be ours, so `denied` can't be relied on to land in the right place
+                log::warn!("{:?} hasn't negotiated a protocol revision, refusing its registration", session);
+                refuse(&mut msg);
+            },
+            Some(Opcode::RegisterCbExt) => {
+                let Some(mem) = msg.body.memory_message_mut() else {
+                    log::error!("RegisterCbExt needs a memory message");
+                    refuse(&mut msg);
+                    continue;
+                };
+                let mut config = match decode::<TtsBackendConfigExt>(mem) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        log::error!("couldn't decode registration: {}", e);
+                        refuse(&mut msg);
+                        continue;
+                    }
+                };
+                let mut sessions = sessions.lock().unwrap();
+                if !sessions.admit(access, session) {
+                    log::warn!("{:?} isn't allowed to register, denying", session);
+                    config.denied = true;
+                    encode(mem, &config).unwrap_or_else(
+                        |e| log::error!("couldn't return registration result: {}", e)
+                    );
+                    continue;
+                }
+                let session = sessions.entry(session);
+                match session.params.merged(&config.prosody) {
+                    Ok(merged) => {
+                        register_callback(session, config.sid, config.op, config.samples_per_cb, config.notice_op);
+                        session.params = SpeechParams { latency: config.latency, ..merged };
+                        log::debug!("registered with {:?}", session.params);
+                    }
+                    Err(param) => {
+                        log::warn!("rejecting registration, {:?} is outside of {:?}", param, param.legal_range());
+                        config.rejected = Some(param);
+                    }
+                }
+                encode(mem, &config).unwrap_or_else(
+                    |e| log::error!("couldn't return registration result: {}", e)
+                );
+            },
+            Some(Opcode::SetParams | Opcode::Stop | Opcode::Status | Opcode::Pause | Opcode::Resume
+                | Opcode::RegisterNoticeCb | Opcode::TextToPhonemes | Opcode::ListVoices | Opcode::SetVoice
+                | Opcode::Quit)
+                if !sessions.lock().unwrap().permits(access, session) => {
+                log::warn!("{:?} isn't allowed to use the server, refusing opcode {}", session, msg.body.id());
+                refuse(&mut msg);
+            },
+            Some(Opcode::SetParams) => {
+                let Some(mem) = msg.body.memory_message_mut() else {
+                    log::error!("SetParams needs a memory message");
+                    refuse(&mut msg);
+                    continue;
+                };
+                let mut request = match decode::<TtsSetParams>(mem) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        log::error!("couldn't decode settings: {}", e);
+                        refuse(&mut msg);
+                        continue;
+                    }
+                };
+                {
+                    let mut sessions = sessions.lock().unwrap();
+                    let pid = session;
+                    let session = sessions.entry(pid);
+                    match session.params.merged(&request.prosody) {
+                        Ok(merged) => {
+                            session.params = SpeechParams { latency: request.latency.or(merged.latency), ..merged };
+                            if let Some(selection) = request.voice.as_ref() {
+                                session.voice = Some(selection.clone());
+                            }
+                            // an utterance of this session in progress picks this up at its next sentence
+                            if queue.lock().unwrap().current().is_some_and(|c| c.session == Some(pid)) {
+                                PARAMS_CHANGED.store(true, Ordering::SeqCst);
+                            }
+                            log::debug!("settings changed to {:?}, voice {:?}", session.params, request.voice);
+                        }
+                        Err(param) => {
+                            log::warn!("rejecting settings, {:?} is outside of {:?}", param, param.legal_range());
+                            request.rejected = Some(param);
+                        }
+                    }
+                }
+                encode(mem, &request).unwrap_or_else(
+                    |e| log::error!("couldn't return settings result: {}", e)
+                );
+            },
+            Some(Opcode::Stop) => {
+                let stopped = stop(policy.scope(session), &queue, synth_cid, &mut handshake);
+                log::info!("speech stopped, cut off {:?}", stopped);
+                let (id, offset) = stopped.unwrap_or((0, 0));
+                xous::return_scalar2(msg.sender, id as usize, offset as usize)
+                    .unwrap_or_else(|e| log::warn!("couldn't return Stop, was it sent blocking? {:?}", e));
+            },
+            Some(Opcode::Status) => {
+                let queue = queue.lock().unwrap();
+                let (state, id, offset) = match (queue.current(), queue.held()) {
+                    (current, _) if ENGINE_HUNG.load(Ordering::SeqCst) =>
+                        (TtsState::Hung, current.map_or(0, |c| c.id), SPOKEN_TO.load(Ordering::SeqCst)),
+                    (Some(current), _) if TTS_SHOULD_ABORT.load(Ordering::SeqCst) =>
+                        (TtsState::Aborting, current.id, SPOKEN_TO.load(Ordering::SeqCst)),
+                    (Some(current), _) if !PAUSE_REQUESTED.load(Ordering::SeqCst) =>
+                        (TtsState::Synthesizing, current.id, SPOKEN_TO.load(Ordering::SeqCst)),
+                    // a pause that hasn't reached the next word yet already counts as paused
+                    (Some(current), _) => (TtsState::Paused, current.id, SPOKEN_TO.load(Ordering::SeqCst)),
+                    (None, Some(held)) => (TtsState::Paused, held.id, held.start),
+                    (None, None) => (TtsState::Idle, 0, 0),
                 };
-                if let Some(wpm) = config.words_per_minute {
-                    words_per_minute.store(wpm as i32, Ordering::SeqCst);
+                xous::return_scalar5(msg.sender,
+                    state.to_usize().unwrap(), id as usize, offset as usize, queue.len(),
+                    handshake.last_latency.as_millis() as usize
+                ).unwrap_or_else(|e| log::warn!("couldn't return Status, was it sent blocking? {:?}", e));
+            },
+            Some(Opcode::Pause) => {
+                let mut queue = queue.lock().unwrap();
+                if queue.pause(policy.scope(session)) {
+                    PAUSE_REQUESTED.store(true, Ordering::SeqCst);
+                }
+                if queue.is_paused() {
+                    log::info!("speech paused");
+                } else {
+                    log::debug!("nothing to pause");
                 }
             },
-            Some(TtsBeOpcode::Quit) => {
+            Some(Opcode::Resume) => {
+                let mut queue = queue.lock().unwrap();
+                if queue.resume(policy.scope(session)) {
+                    // a pause that hasn't reached the next word yet is called off, unless another one still holds the utterance
+                    if !queue.current().is_some_and(|c| queue.holds(c.session, c.priority)) {
+                        PAUSE_REQUESTED.store(false, Ordering::SeqCst);
+                    }
+                    if queue.has_ready() && TTS_RUNNING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                        notify_synth(synth_cid, SynthOp::NewString, 0);
+                    }
+                    log::info!("speech resumed");
+                }
+            },
+            Some(Opcode::UnregisterCb) => {
+                stop(Some(session), &queue, synth_cid, &mut handshake);
+                // the synth thread closes the connection, so it can't be pulled out from under it
+                notify_synth(synth_cid, SynthOp::Unregister, session.get() as usize);
+            },
+            Some(Opcode::Authorize) => {
+                let token = msg.body.scalar_message()
+                    .map(|s| [s.arg1 as u32, s.arg2 as u32, s.arg3 as u32, s.arg4 as u32])
+                    .unwrap_or_default();
+                let mut sessions = sessions.lock().unwrap();
+                if let AccessPolicy::Tokens(_) = access {
+                    if access.accepts(token) {
+                        log::info!("{:?} authorized", session);
+                        sessions.entry(session).authorized = true;
+                    } else {
+                        log::warn!("{:?} presented a bad token", session);
+                    }
+                }
+                xous::return_scalar(msg.sender, sessions.permits(access, session) as usize)
+                    .unwrap_or_else(|e| log::warn!("couldn't return Authorize, was it sent blocking? {:?}", e));
+            },
+            Some(Opcode::Negotiate) => {
+                let (revision, required) = msg.body.scalar_message()
+                    .map(|s| (s.arg1 as u32, s.arg2 as u32))
+                    .unwrap_or_default();
+                let supported = TtsCapability::supported();
+                let answer = TtsNegotiation::decide(revision, required, supported);
+                if answer == TtsNegotiation::Accepted {
+                    log::debug!("{:?} speaks protocol revision {}", session, revision);
+                } else {
+                    log::warn!("{:?} asked for revision {} with capabilities {:#x}: {:?}", session, revision, required, answer);
+                }
+                sessions.lock().unwrap().entry(session).negotiation = Some(answer);
+                xous::return_scalar5(msg.sender,
+                    answer.to_usize().unwrap(), PROTOCOL_REVISION as usize, MIN_PROTOCOL_REVISION as usize,
+                    supported as usize, 0
+                ).unwrap_or_else(|e| log::warn!("couldn't return Negotiate, was it sent blocking? {:?}", e));
+            },
+            Some(Opcode::RegisterNoticeCb) => {
+                if let Some(op) = msg.body.scalar_message().map(|s| s.arg1 as u32) {
+                    match sessions.lock().unwrap().entry(session).callback.as_mut() {
+                        Some(cb) => cb.notice_op = Some(op),
+                        None => log::warn!("RegisterNoticeCb received before RegisterCb, ignoring"),
+                    }
+                }
+            },
+            Some(Opcode::TextToPhonemes) | Some(Opcode::ListVoices) | Some(Opcode::SetVoice) => {
+                if ENGINE_HUNG.load(Ordering::SeqCst) {
+                    // the synth thread would never get to it
+                    log::warn!("the engine is hung, refusing engine query");
+                    refuse(&mut msg);
+                } else if msg.body.memory_message_mut().is_some() {
+                    match request_tx.send(msg) {
+                        Ok(()) => notify_synth(synth_cid, SynthOp::Request, 0),
+                        Err(std::sync::mpsc::SendError(mut msg)) => {
+                            log::error!("synth thread has gone away, refusing engine query");
+                            refuse(&mut msg);
+                        }
+                    }
+                } else {
+                    log::error!("engine queries must be lent mutably");
+                    refuse(&mut msg);
+                }
+            },
+            Some(Opcode::Quit) => {
                 log::warn!("server quitting");
-                xous::return_scalar(msg.sender, 1).unwrap();
+                stop(None, &queue, synth_cid, &mut handshake);
+                notify_synth(synth_cid, SynthOp::Quit, 0);
+                // a synth thread stuck inside espeak never gets to the message, so don't wait on it forever
+                match done_rx.recv_timeout(engine_config.watchdog_timeout) {
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) =>
+                        log::error!("synth thread didn't stop within {:?}, leaving it behind", engine_config.watchdog_timeout),
+                    _ => if synth_thread.join().is_err() {
+                        log::error!("synth thread panicked");
+                    },
+                }
+                for (pid, session) in sessions.lock().unwrap().drain() {
+                    if let Some(cb) = session.callback {
+                        // clients that didn't ask for notices at least see their audio stream end
+                        let told = match cb.notice_op {
+                            Some(_) => send_notice(&cb, TtsBeNotice::Quit),
+                            None => send_block(&cb, &[], Some(TtsBeControl::Abort)),
+                        };
+                        told.unwrap_or_else(
+                            |e| log::warn!("couldn't tell {:?} about the shutdown: {:?}", pid, e)
+                        );
+                        unsafe { xous::disconnect(cb.cid) }.unwrap_or_else(
+                            |e| log::warn!("couldn't disconnect from {:?}: {:?}", pid, e)
+                        );
+                    }
+                }
+                unsafe { xous::disconnect(synth_cid) }.ok();
+                log::info!("server shut down");
+                xous::return_scalar(msg.sender, 1).unwrap_or_else(
+                    |e| log::warn!("couldn't return Quit, was it sent blocking? {:?}", e)
+                );
                 break;
             }
             None => {
                 log::error!("couldn't convert opcode: {:?}", msg);
+                refuse(&mut msg);
             }
         }
     }
@@ -237,3 +1466,68 @@ fn main() -> ! {
     log::trace!("quitting");
     xous::terminate_process(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `send_chunks` and returns the length of each block sent, with its control message.
+    fn blocks(samples_per_cb: Option<u32>, pending: &mut Vec<u16>, samples: &[u16], control: Option<TtsBeControl>) -> Vec<(usize, Option<String>)> {
+        let mut sent = Vec::new();
+        send_chunks(samples_per_cb, pending, samples, control, |block: &[u16], control: Option<TtsBeControl>| {
+            sent.push((block.len(), control.map(|c| format!("{:?}", c))));
+            Ok::<(), ()>(())
+        }).unwrap();
+        sent
+    }
+
+    #[test]
+    fn chunks_pass_through_when_no_block_size_is_set() {
+        let mut pending = Vec::new();
+        assert_eq!(blocks(None, &mut pending, &[0; 300], None), vec![(300, None)]);
+        let long = vec![0; MAX_WAV_BUF_SAMPLES + 10];
+        assert_eq!(
+            blocks(None, &mut pending, &long, Some(TtsBeControl::End)),
+            vec![(MAX_WAV_BUF_SAMPLES, None), (10, Some(String::from("End")))]
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn audio_is_rechunked_into_full_blocks() {
+        let mut pending = Vec::new();
+        assert_eq!(blocks(Some(100), &mut pending, &[0; 250], None), vec![(100, None), (100, None)]);
+        assert_eq!(pending.len(), 50);
+        assert_eq!(blocks(Some(100), &mut pending, &[0; 30], None), vec![]);
+        assert_eq!(pending.len(), 80);
+        assert_eq!(blocks(Some(100), &mut pending, &[0; 20], None), vec![(100, None)]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn end_flushes_the_partial_block_and_abort_drops_it() {
+        let mut pending = Vec::new();
+        blocks(Some(100), &mut pending, &[0; 150], None);
+        assert_eq!(
+            blocks(Some(100), &mut pending, &[], Some(TtsBeControl::End)),
+            vec![(50, Some(String::from("End")))]
+        );
+        assert!(pending.is_empty());
+        blocks(Some(100), &mut pending, &[0; 150], None);
+        assert_eq!(
+            blocks(Some(100), &mut pending, &[0; 100], Some(TtsBeControl::Abort)),
+            vec![(0, Some(String::from("Abort")))]
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn block_size_is_capped_to_what_fits_in_a_message() {
+        let mut pending = Vec::new();
+        let long = vec![0; MAX_WAV_BUF_SAMPLES * 2];
+        assert_eq!(
+            blocks(Some(u32::MAX), &mut pending, &long, None),
+            vec![(MAX_WAV_BUF_SAMPLES, None), (MAX_WAV_BUF_SAMPLES, None)]
+        );
+    }
+}
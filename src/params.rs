@@ -0,0 +1,119 @@
+use crate::api::*;
+use crate::bindings::*;
+use crate::error::EspeakError;
+
+/// The prosody settings in effect, with espeak's defaults for anything the client hasn't set.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SpeechParams {
+    pub words_per_minute: u32,
+    pub pitch: u32,
+    pub range: u32,
+    pub volume: u32,
+    pub word_gap: u32,
+    pub emphasis: u32,
+    /// the client's buffering preset; `None` uses the engine's configured one. This resizes
+    /// espeak's buffers, so unlike the rest it's only applied between utterances.
+    pub latency: Option<TtsLatency>,
+}
+
+impl Default for SpeechParams {
+    fn default() -> Self {
+        SpeechParams {
+            words_per_minute: 175,
+            pitch: 50,
+            range: 50,
+            volume: 100,
+            word_gap: 0,
+            emphasis: 0,
+            latency: None,
+        }
+    }
+}
+
+impl SpeechParams {
+    /// Returns these parameters with every change in `prosody` applied, or the first
+    /// parameter that is outside of its legal range.
+    pub fn merged(&self, prosody: &TtsProsody) -> Result<SpeechParams, TtsParam> {
+        let check = |param: TtsParam, value: Option<u32>, current: u32| match value {
+            Some(v) if !param.legal_range().contains(&v) => Err(param),
+            Some(v) => Ok(v),
+            None => Ok(current),
+        };
+        Ok(SpeechParams {
+            words_per_minute: check(TtsParam::Rate, prosody.words_per_minute, self.words_per_minute)?,
+            pitch: check(TtsParam::Pitch, prosody.pitch, self.pitch)?,
+            range: check(TtsParam::Range, prosody.range, self.range)?,
+            volume: check(TtsParam::Volume, prosody.volume, self.volume)?,
+            word_gap: check(TtsParam::WordGap, prosody.word_gap, self.word_gap)?,
+            emphasis: check(TtsParam::Emphasis, prosody.emphasis, self.emphasis)?,
+            latency: self.latency,
+        })
+    }
+
+    /// Pushes the prosody into the engine. It takes effect from the next clause on.
+    pub fn apply(&self) -> Result<(), EspeakError> {
+        for (param, value) in [
+            (espeakRATE, self.words_per_minute),
+            (espeakPITCH, self.pitch),
+            (espeakRANGE, self.range),
+            (espeakVOLUME, self.volume),
+            (espeakWORDGAP, self.word_gap),
+            (espeakEMPHASIS, self.emphasis),
+        ] {
+            EspeakError::check(unsafe { espeak_ng_SetParameter(param, value as c_int, 0) })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_applies_only_what_is_set() {
+        let prosody = TtsProsody { pitch: Some(70), emphasis: Some(5), ..Default::default() };
+        let merged = SpeechParams::default().merged(&prosody).unwrap();
+        assert_eq!(merged, SpeechParams { pitch: 70, emphasis: 5, ..Default::default() });
+    }
+
+    #[test]
+    fn merged_accepts_the_ends_of_each_range() {
+        for param in [TtsParam::Rate, TtsParam::Pitch, TtsParam::Range, TtsParam::Volume, TtsParam::WordGap, TtsParam::Emphasis] {
+            for value in [*param.legal_range().start(), *param.legal_range().end()] {
+                let prosody = prosody_with(param, value);
+                assert!(SpeechParams::default().merged(&prosody).is_ok(), "{:?} {}", param, value);
+            }
+        }
+    }
+
+    #[test]
+    fn merged_rejects_out_of_range_values() {
+        for param in [TtsParam::Rate, TtsParam::Pitch, TtsParam::Range, TtsParam::Volume, TtsParam::WordGap, TtsParam::Emphasis] {
+            let range = param.legal_range();
+            let below = range.start().checked_sub(1).map(|v| prosody_with(param, v));
+            for prosody in below.into_iter().chain([prosody_with(param, range.end() + 1)]) {
+                assert_eq!(SpeechParams::default().merged(&prosody), Err(param));
+            }
+        }
+    }
+
+    #[test]
+    fn merged_reports_the_first_bad_parameter() {
+        let prosody = TtsProsody { pitch: Some(101), volume: Some(1000), ..Default::default() };
+        assert_eq!(SpeechParams::default().merged(&prosody), Err(TtsParam::Pitch));
+    }
+
+    fn prosody_with(param: TtsParam, value: u32) -> TtsProsody {
+        let mut prosody = TtsProsody::default();
+        *match param {
+            TtsParam::Rate => &mut prosody.words_per_minute,
+            TtsParam::Pitch => &mut prosody.pitch,
+            TtsParam::Range => &mut prosody.range,
+            TtsParam::Volume => &mut prosody.volume,
+            TtsParam::WordGap => &mut prosody.word_gap,
+            TtsParam::Emphasis => &mut prosody.emphasis,
+        } = Some(value);
+        prosody
+    }
+}
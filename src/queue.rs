@@ -0,0 +1,298 @@
+use std::collections::VecDeque;
+use xous::PID;
+use crate::api::*;
+
+/// A piece of text to be spoken, along with any settings that apply to it alone.
+#[derive(Debug, Default)]
+pub struct Utterance {
+    pub id: u32,
+    /// the session that asked for it, which decides the settings and where the audio goes
+    pub session: Option<PID>,
+    pub text: String,
+    pub overrides: TtsOverrides,
+    pub priority: TtsPriority,
+    /// characters of the original text that were already spoken before `text`; only non-zero
+    /// for what is left of a preempted or paused utterance
+    pub start: u32,
+    /// samples already delivered for the part of the text before `start`
+    pub samples: u32,
+    /// audio of the part before `start` that was held back for a `samples_per_cb` block
+    pub pending: Vec<u16>,
+    /// the envelope of a `StrToWavSync` caller that is waiting for the outcome
+    pub reply: Option<xous::MessageEnvelope>,
+}
+
+/// What the synth thread is working on.
+#[derive(Debug, Copy, Clone)]
+pub struct CurrentUtterance {
+    pub id: u32,
+    pub session: Option<PID>,
+    pub priority: TtsPriority,
+}
+
+/// Utterances waiting for the synth thread, highest priority first and in arrival order within
+/// a priority. Also tracks the utterance the synth thread is working on, so that new requests
+/// can be weighed against it.
+#[derive(Debug, Default)]
+pub struct SpeechQueue {
+    pending: VecDeque<Utterance>,
+    current: Option<CurrentUtterance>,
+    last_id: u32,
+    /// pauses in effect: utterances at or below the priority, from the session if one is given,
+    /// are held in the queue
+    holds: Vec<(Option<PID>, TtsPriority)>,
+    /// session, ID and offset of flushed utterances that had started playing, whose clients
+    /// haven't been told yet
+    cut_off: Vec<(PID, u32, u32)>,
+}
+
+impl SpeechQueue {
+    /// A fresh utterance ID. IDs are never 0, so 0 can stand for "no utterance" over IPC.
+    pub fn next_id(&mut self) -> u32 {
+        self.last_id = self.last_id.checked_add(1).unwrap_or(1);
+        self.last_id
+    }
+
+    /// Queues `utterance` behind everything of the same or a higher priority.
+    pub fn push(&mut self, utterance: Utterance) {
+        let index = self.pending.iter().position(|u| u.priority < utterance.priority).unwrap_or(self.pending.len());
+        self.pending.insert(index, utterance);
+    }
+
+    /// Queues `utterance` ahead of everything else of its priority; used to put a preempted
+    /// utterance back where it was.
+    pub fn push_front(&mut self, utterance: Utterance) {
+        let index = self.pending.iter().position(|u| u.priority <= utterance.priority).unwrap_or(self.pending.len());
+        self.pending.insert(index, utterance);
+    }
+
+    /// Takes the next utterance that isn't held by a pause and marks it as the current one.
+    pub fn pop(&mut self) -> Option<Utterance> {
+        let index = self.pending.iter().position(|u| !self.holds(u.session, u.priority))?;
+        let next = self.pending.remove(index);
+        self.current = next.as_ref().map(|u| CurrentUtterance { id: u.id, session: u.session, priority: u.priority });
+        next
+    }
+
+    /// Called by the synth thread once it is done with the current utterance, however that ended.
+    pub fn finish(&mut self) -> Option<CurrentUtterance> {
+        self.current.take()
+    }
+
+    /// The utterance being spoken, if any.
+    pub fn current(&self) -> Option<CurrentUtterance> {
+        self.current
+    }
+
+    /// Takes everything waiting at `priority` out of the queue, only from `session` if one is given.
+    pub fn flush(&mut self, priority: TtsPriority, session: Option<PID>) -> Vec<Utterance> {
+        self.flush_where(|u| u.priority == priority && session.is_none_or(|s| u.session == Some(s)))
+    }
+
+    /// Takes everything waiting out of the queue, whatever its priority, only from `session` if one is given.
+    pub fn flush_all(&mut self, session: Option<PID>) -> Vec<Utterance> {
+        self.flush_where(|u| session.is_none_or(|s| u.session == Some(s)))
+    }
+
+    fn flush_where(&mut self, flush: impl Fn(&Utterance) -> bool) -> Vec<Utterance> {
+        let (flushed, kept): (VecDeque<_>, _) = self.pending.drain(..).partition(flush);
+        self.pending = kept;
+        self.cut_off.extend(flushed.iter()
+            .filter(|u| u.start > 0)
+            .filter_map(|u| u.session.map(|pid| (pid, u.id, u.start))));
+        flushed.into()
+    }
+
+    /// The flushed utterances that had started playing since the last call, as session, ID and
+    /// the offset they were cut off at.
+    pub fn take_cut_off(&mut self) -> Vec<(PID, u32, u32)> {
+        std::mem::take(&mut self.cut_off)
+    }
+
+    /// True if a session other than `session` has speech playing or queued.
+    pub fn busy_for_others(&self, session: Option<PID>) -> bool {
+        self.current.is_some_and(|c| c.session != session) || self.pending.iter().any(|u| u.session != session)
+    }
+
+    /// True if something at `priority` or above is being spoken or is waiting to be.
+    pub fn busy_at(&self, priority: TtsPriority) -> bool {
+        self.current.is_some_and(|c| c.priority >= priority) || self.pending.front().is_some_and(|u| u.priority >= priority)
+    }
+
+    /// True if there's an utterance waiting that isn't held by a pause.
+    pub fn has_ready(&self) -> bool {
+        self.pending.iter().any(|u| !self.holds(u.session, u.priority))
+    }
+
+    /// True if a pause holds speech from `session` at `priority`.
+    pub fn holds(&self, session: Option<PID>, priority: TtsPriority) -> bool {
+        self.holds.iter().any(|&(scope, level)| priority <= level && scope.is_none_or(|s| session == Some(s)))
+    }
+
+    /// Holds everything at or below the priority of the utterance being spoken (or of the next one
+    /// if nothing is), so that only speech that outranks it gets through until `resume`. With a
+    /// `scope`, only that session's speech is weighed and held. Returns true if the current
+    /// utterance has to be stopped for that.
+    pub fn pause(&mut self, scope: Option<PID>) -> bool {
+        let in_scope = |session: Option<PID>| scope.is_none_or(|s| session == Some(s));
+        let current = self.current.filter(|c| in_scope(c.session));
+        let level = current.map(|c| c.priority)
+            .or(self.pending.iter().find(|u| in_scope(u.session)).map(|u| u.priority));
+        let Some(level) = level else {
+            return false;
+        };
+        match self.holds.iter_mut().find(|(s, _)| *s == scope) {
+            Some((_, held)) => *held = level.max(*held),
+            None => self.holds.push((scope, level)),
+        }
+        current.is_some_and(|c| self.holds(c.session, c.priority))
+    }
+
+    /// Lifts the pause of `scope`, or every pause if there's no scope. Returns true if there was one.
+    pub fn resume(&mut self, scope: Option<PID>) -> bool {
+        let before = self.holds.len();
+        self.holds.retain(|(s, _)| scope.is_some() && *s != scope);
+        self.holds.len() != before
+    }
+
+    pub fn is_paused(&self) -> bool {
+        !self.holds.is_empty()
+    }
+
+    /// The utterance that will carry on once speech is resumed, if nothing else is ready to play.
+    pub fn held(&self) -> Option<&Utterance> {
+        self.pending.iter().find(|u| self.holds(u.session, u.priority)).filter(|_| !self.has_ready())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utterance(queue: &mut SpeechQueue, session: u8, priority: TtsPriority) -> Utterance {
+        Utterance { id: queue.next_id(), session: PID::new(session), priority, ..Default::default() }
+    }
+
+    /// Has the synth thread take the next utterance and finish it right away.
+    fn speak(queue: &mut SpeechQueue) -> Option<u32> {
+        let id = queue.pop().map(|u| u.id);
+        queue.finish();
+        id
+    }
+
+    #[test]
+    fn higher_priorities_go_first() {
+        let mut queue = SpeechQueue::default();
+        let mut ids = Vec::new();
+        for priority in [TtsPriority::Background, TtsPriority::Interactive, TtsPriority::Alert, TtsPriority::Interactive] {
+            let u = utterance(&mut queue, 1, priority);
+            ids.push(u.id);
+            queue.push(u);
+        }
+        let spoken: Vec<_> = std::iter::from_fn(|| speak(&mut queue)).collect();
+        assert_eq!(spoken, [ids[2], ids[1], ids[3], ids[0]]);
+    }
+
+    #[test]
+    fn preempted_remainder_goes_ahead_of_its_priority() {
+        let mut queue = SpeechQueue::default();
+        let first = utterance(&mut queue, 1, TtsPriority::Interactive);
+        let second = utterance(&mut queue, 1, TtsPriority::Interactive);
+        let (first_id, second_id) = (first.id, second.id);
+        queue.push(first);
+        queue.push(second);
+        let mut playing = queue.pop().unwrap();
+        let alert = utterance(&mut queue, 1, TtsPriority::Alert);
+        let alert_id = alert.id;
+        queue.push(alert);
+        queue.finish();
+        playing.start = 5;
+        queue.push_front(playing);
+        assert_eq!(queue.pop().map(|u| u.id), Some(alert_id));
+        queue.finish();
+        let resumed = queue.pop().unwrap();
+        assert_eq!((resumed.id, resumed.start), (first_id, 5));
+        queue.finish();
+        assert_eq!(speak(&mut queue), Some(second_id));
+        assert_eq!(speak(&mut queue), None);
+    }
+
+    #[test]
+    fn pause_holds_only_lower_or_equal_priorities() {
+        let mut queue = SpeechQueue::default();
+        let interactive = utterance(&mut queue, 1, TtsPriority::Interactive);
+        let background = utterance(&mut queue, 1, TtsPriority::Background);
+        let interactive_id = interactive.id;
+        queue.push(interactive);
+        queue.push(background);
+        let mut playing = queue.pop().unwrap();
+        assert!(queue.pause(None));
+        queue.finish();
+        playing.start = 3;
+        queue.push_front(playing);
+        assert!(!queue.has_ready());
+        assert_eq!(queue.pop().map(|u| u.id), None);
+
+        let alert = utterance(&mut queue, 1, TtsPriority::Alert);
+        let alert_id = alert.id;
+        queue.push(alert);
+        assert!(queue.has_ready());
+        assert_eq!(speak(&mut queue), Some(alert_id));
+        assert_eq!(queue.held().map(|u| (u.id, u.start)), Some((interactive_id, 3)));
+
+        assert!(queue.resume(None));
+        assert!(!queue.is_paused());
+        assert_eq!(speak(&mut queue), Some(interactive_id));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn pause_of_one_session_leaves_the_others_speaking() {
+        let mut queue = SpeechQueue::default();
+        let mine = utterance(&mut queue, 1, TtsPriority::Interactive);
+        let theirs = utterance(&mut queue, 2, TtsPriority::Interactive);
+        let (mine_id, theirs_id) = (mine.id, theirs.id);
+        queue.push(mine);
+        queue.push(theirs);
+        assert!(!queue.pause(PID::new(2)));
+        assert_eq!(speak(&mut queue), Some(mine_id));
+        assert_eq!(speak(&mut queue), None);
+        assert!(!queue.resume(PID::new(1)));
+        assert!(queue.resume(PID::new(2)));
+        assert_eq!(speak(&mut queue), Some(theirs_id));
+    }
+
+    #[test]
+    fn flushing_a_started_utterance_notes_where_it_was_cut_off() {
+        let mut queue = SpeechQueue::default();
+        let mut started = utterance(&mut queue, 1, TtsPriority::Background);
+        started.start = 7;
+        let started_id = started.id;
+        queue.push(started);
+        let fresh = utterance(&mut queue, 2, TtsPriority::Background);
+        queue.push(fresh);
+        assert_eq!(queue.flush(TtsPriority::Background, None).len(), 2);
+        assert_eq!(queue.take_cut_off(), [(PID::new(1).unwrap(), started_id, 7)]);
+        assert!(queue.take_cut_off().is_empty());
+    }
+
+    #[test]
+    fn busy_at_counts_the_current_and_the_queued() {
+        let mut queue = SpeechQueue::default();
+        assert!(!queue.busy_at(TtsPriority::Background));
+        let u = utterance(&mut queue, 1, TtsPriority::Interactive);
+        queue.push(u);
+        assert!(queue.busy_at(TtsPriority::Background));
+        assert!(queue.busy_at(TtsPriority::Interactive));
+        assert!(!queue.busy_at(TtsPriority::Alert));
+        queue.pop();
+        assert!(queue.busy_at(TtsPriority::Interactive));
+        assert!(!queue.busy_at(TtsPriority::Alert));
+        queue.finish();
+        assert!(!queue.busy_at(TtsPriority::Background));
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use xous::{SID, CID, PID};
+use crate::api::*;
+use crate::params::SpeechParams;
+
+/// How speech from different sessions shares the engine.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MixingPolicy {
+    /// one session at a time: while a session has speech playing or queued, utterances from
+    /// every other session are dropped
+    Exclusive,
+    /// sessions take turns through the queue, and an interruption only cuts off the session's
+    /// own speech
+    Queued,
+    /// the latest request wins: an interruption cuts off speech of its priority from any session
+    Preemptive,
+}
+
+impl MixingPolicy {
+    /// The session whose speech a request from `session` may interrupt or stop; `None` means every session's.
+    pub fn scope(&self, session: PID) -> Option<PID> {
+        match self {
+            MixingPolicy::Preemptive => None,
+            MixingPolicy::Exclusive | MixingPolicy::Queued => Some(session),
+        }
+    }
+}
+
+/// Which processes may register a callback and have text spoken. Xous PIDs are the only identity
+/// a request carries, so a grant belongs to a PID and goes away with its session.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessPolicy {
+    /// any process that can connect to the server
+    Open,
+    /// only the first process to register a callback, for as long as the server runs; everyone
+    /// else is turned away, even after that client has gone
+    FirstClient,
+    /// only processes that presented one of these tokens with `Authorize`
+    Tokens(&'static [[u32; 4]]),
+}
+
+#[cfg(all(feature = "access-first-client", feature = "access-tokens"))]
+compile_error!("the access-first-client and access-tokens features are mutually exclusive");
+
+const _: () = assert!(
+    !cfg!(feature = "access-tokens") || match option_env!("TTS_ACCESS_TOKENS") {
+        Some(list) => valid_tokens(list),
+        None => false,
+    },
+    "the access-tokens feature needs TTS_ACCESS_TOKENS set to a comma separated list of 32 hex digit tokens"
+);
+
+impl AccessPolicy {
+    /// The policy picked at build time: the `access-first-client` or `access-tokens` feature, or
+    /// `Open` without either. The tokens are read from `TTS_ACCESS_TOKENS` when the server is
+    /// built, as a comma separated list of 32 hex digits each, the first 8 being `arg1` of
+    /// `Authorize`; the build fails if the list is missing or malformed.
+    pub fn configured() -> AccessPolicy {
+        if cfg!(feature = "access-first-client") {
+            AccessPolicy::FirstClient
+        } else if cfg!(feature = "access-tokens") {
+            // can't come up empty, the list was checked at build time
+            let tokens = parse_tokens(option_env!("TTS_ACCESS_TOKENS").unwrap_or("")).unwrap_or_default();
+            AccessPolicy::Tokens(Box::leak(tokens.into_boxed_slice()))
+        } else {
+            AccessPolicy::Open
+        }
+    }
+
+    /// Whether `token` is one of the configured tokens. Every token is compared in full, so the
+    /// time taken doesn't tell how close a guess came.
+    pub fn accepts(&self, token: [u32; 4]) -> bool {
+        match self {
+            AccessPolicy::Tokens(tokens) => tokens.iter().fold(false, |found, candidate| {
+                let diff = candidate.iter().zip(token.iter()).fold(0, |acc, (a, b)| acc | (a ^ b));
+                found | (diff == 0)
+            }),
+            AccessPolicy::Open | AccessPolicy::FirstClient => true,
+        }
+    }
+}
+
+/// Whether `list` holds at least one token and nothing but tokens, in the format `parse_tokens`
+/// reads. A const fn, so that a bad list fails the build instead of locking everyone out.
+const fn valid_tokens(list: &str) -> bool {
+    let bytes = list.as_bytes();
+    let (mut tokens, mut digits, mut closed) = (0, 0, false);
+    let mut i = 0;
+    while i <= bytes.len() {
+        let b = if i < bytes.len() { bytes[i] } else { b',' };
+        if b == b',' {
+            match digits {
+                0 => (),
+                32 => tokens += 1,
+                _ => return false,
+            }
+            digits = 0;
+            closed = false;
+        } else if b.is_ascii_whitespace() {
+            closed = digits > 0;
+        } else if b.is_ascii_hexdigit() && !closed {
+            digits += 1;
+        } else {
+            return false;
+        }
+        i += 1;
+    }
+    tokens > 0
+}
+
+fn parse_tokens(list: &str) -> Option<Vec<[u32; 4]>> {
+    list.split(',').map(str::trim_ascii).filter(|token| !token.is_empty()).map(|token| {
+        if token.len() != 32 || !token.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let value = u128::from_str_radix(token, 16).ok()?;
+        Some([(value >> 96) as u32, (value >> 64) as u32, (value >> 32) as u32, value as u32])
+    }).collect()
+}
+
+#[derive(Copy, Clone)]
+pub struct Callback {
+    /// the client's server, which tells a new registration apart from the one before
+    pub sid: SID,
+    pub cid: CID,
+    pub op: u32,
+    /// if set, audio is delivered in blocks of exactly this many samples, apart from the last one
+    pub samples_per_cb: Option<u32>,
+    /// opcode for `TtsBeNotice` messages, if the client asked for them
+    pub notice_op: Option<u32>,
+}
+
+/// Everything the server keeps for one client process.
+#[derive(Default)]
+pub struct Session {
+    /// where the session's audio goes; its utterances are dropped until it registers one
+    pub callback: Option<Callback>,
+    pub params: SpeechParams,
+    /// `None` uses the configured default voice
+    pub voice: Option<TtsVoiceSelect>,
+    /// presented a valid token, see `AccessPolicy::Tokens`
+    pub authorized: bool,
+    /// the answer to the session's last `Negotiate`, if it sent one
+    pub negotiation: Option<TtsNegotiation>,
+    /// when the session was started, counting sessions; the oldest unregistered one goes first
+    opened: u64,
+}
+
+/// How many sessions without a callback are kept. Xous doesn't tell the server when a process
+/// exits, so a client that negotiates, authorizes or changes settings but never registers would
+/// otherwise leave its session behind for good. Past this, the oldest such session is forgotten.
+const MAX_UNREGISTERED: usize = 16;
+
+/// The client sessions, keyed by the PID of the process that sent the request.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<PID, Session>,
+    /// the process that registered first, see `AccessPolicy::FirstClient`
+    owner: Option<PID>,
+    /// sessions started so far
+    opened: u64,
+}
+
+impl Sessions {
+    pub fn get(&self, pid: PID) -> Option<&Session> {
+        self.sessions.get(&pid)
+    }
+
+    /// The session for `pid`, starting a fresh one with the default settings if there isn't one yet.
+    pub fn entry(&mut self, pid: PID) -> &mut Session {
+        if !self.sessions.contains_key(&pid) {
+            self.forget_unregistered();
+        }
+        let opened = &mut self.opened;
+        self.sessions.entry(pid).or_insert_with(|| {
+            *opened += 1;
+            Session { opened: *opened, ..Default::default() }
+        })
+    }
+
+    /// Makes room for one more session without a callback, see `MAX_UNREGISTERED`.
+    fn forget_unregistered(&mut self) {
+        let unregistered = self.sessions.iter().filter(|(_, s)| s.callback.is_none());
+        if unregistered.clone().count() < MAX_UNREGISTERED {
+            return;
+        }
+        if let Some(pid) = unregistered.min_by_key(|(_, s)| s.opened).map(|(pid, _)| *pid) {
+            log::info!("forgetting {:?}, which never registered a callback", pid);
+            self.sessions.remove(&pid);
+        }
+    }
+
+    pub fn remove(&mut self, pid: PID) -> Option<Session> {
+        self.sessions.remove(&pid)
+    }
+
+    /// Whether `pid` may register a callback or have text spoken under `policy`.
+    pub fn permits(&self, policy: AccessPolicy, pid: PID) -> bool {
+        match policy {
+            AccessPolicy::Open => true,
+            AccessPolicy::FirstClient => self.owner.is_none_or(|owner| owner == pid),
+            AccessPolicy::Tokens(_) => self.get(pid).is_some_and(|s| s.authorized),
+        }
+    }
+
+    /// The answer to `pid`'s last `Negotiate`, or `None` if it never sent one.
+    pub fn negotiation(&self, pid: PID) -> Option<TtsNegotiation> {
+        self.get(pid).and_then(|s| s.negotiation)
+    }
+
+    /// Checks whether `pid` may register under `policy`, and if it's the first to do so under
+    /// `FirstClient`, makes it the owner.
+    pub fn admit(&mut self, policy: AccessPolicy, pid: PID) -> bool {
+        if !self.permits(policy, pid) {
+            return false;
+        }
+        if policy == AccessPolicy::FirstClient && self.owner.is_none() {
+            log::info!("{:?} is now the only client allowed to speak", pid);
+            self.owner = Some(pid);
+        }
+        true
+    }
+
+    pub fn callback(&self, pid: PID) -> Option<Callback> {
+        self.get(pid).and_then(|s| s.callback)
+    }
+
+    /// The session's settings, or the defaults if `pid` has no session.
+    pub fn params(&self, pid: Option<PID>) -> SpeechParams {
+        pid.and_then(|pid| self.get(pid)).map(|s| s.params).unwrap_or_default()
+    }
+
+    pub fn voice(&self, pid: Option<PID>) -> Option<TtsVoiceSelect> {
+        pid.and_then(|pid| self.get(pid)).and_then(|s| s.voice.clone())
+    }
+
+    /// Takes every session out, for shutdown.
+    pub fn drain(&mut self) -> impl Iterator<Item = (PID, Session)> + '_ {
+        self.sessions.drain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef0011223344556677";
+
+    #[test]
+    fn tokens_are_read_most_significant_word_first() {
+        let list = format!(" {}, FFFFFFFF000000000000000000000001 ,", TOKEN);
+        assert!(valid_tokens(&list));
+        assert_eq!(parse_tokens(&list), Some(vec![
+            [0x0123_4567, 0x89ab_cdef, 0x0011_2233, 0x4455_6677],
+            [0xffff_ffff, 0, 0, 1],
+        ]));
+    }
+
+    #[test]
+    fn malformed_token_lists_are_rejected() {
+        for list in ["", " , ", &TOKEN[1..], &format!("{}0", TOKEN), &format!("+{}", &TOKEN[1..]),
+            &format!("{} {}", &TOKEN[..16], &TOKEN[16..]), &format!("{},x", TOKEN)] {
+            assert!(!valid_tokens(list), "{:?}", list);
+        }
+        assert_eq!(parse_tokens(&TOKEN[1..]), None);
+        assert_eq!(parse_tokens(&format!("+{}", &TOKEN[1..])), None);
+    }
+
+    #[test]
+    fn only_configured_tokens_are_accepted() {
+        let policy = AccessPolicy::Tokens(&[[1, 2, 3, 4], [5, 6, 7, 8]]);
+        assert!(policy.accepts([5, 6, 7, 8]));
+        assert!(!policy.accepts([5, 6, 7, 9]));
+        assert!(!policy.accepts([0; 4]));
+        assert!(!AccessPolicy::Tokens(&[]).accepts([0; 4]));
+        assert!(AccessPolicy::Open.accepts([0; 4]));
+    }
+
+    #[test]
+    fn the_oldest_unregistered_session_is_forgotten() {
+        let mut sessions = Sessions::default();
+        for pid in 1..=MAX_UNREGISTERED as u8 {
+            sessions.entry(PID::new(pid).unwrap()).params.pitch = pid as u32;
+        }
+        assert!(sessions.get(PID::new(1).unwrap()).is_some());
+        sessions.entry(PID::new(100).unwrap());
+        assert!(sessions.get(PID::new(1).unwrap()).is_none());
+        assert!(sessions.get(PID::new(2).unwrap()).is_some());
+        // an existing session doesn't make room
+        sessions.entry(PID::new(2).unwrap());
+        assert!(sessions.get(PID::new(3).unwrap()).is_some());
+    }
+}
@@ -0,0 +1,95 @@
+use crate::bindings::*;
+use crate::api::*;
+use crate::error::EspeakError;
+
+impl From<c_uchar> for TtsGender {
+    fn from(gender: c_uchar) -> Self {
+        match gender {
+            1 => TtsGender::Male,
+            2 => TtsGender::Female,
+            _ => TtsGender::Unspecified,
+        }
+    }
+}
+
+/// Walks espeak's packed language list: a priority byte followed by a NUL-terminated tag,
+/// repeated until a priority of 0.
+unsafe fn languages_from_c(mut ptr: *const c_char) -> Vec<TtsVoiceLanguage> {
+    let mut languages = Vec::new();
+    if ptr.is_null() {
+        return languages;
+    }
+    loop {
+        let priority = *ptr as u8;
+        if priority == 0 {
+            break;
+        }
+        ptr = ptr.add(1);
+        let language = string_from_c(ptr);
+        ptr = ptr.add(std::ffi::CStr::from_ptr(ptr as *const core::ffi::c_char).to_bytes().len() + 1);
+        languages.push(TtsVoiceLanguage { priority, language });
+    }
+    languages
+}
+
+/// All voices compiled into this build, as reported by `espeak_ListVoices`. The engine must
+/// already be set up; everything is copied out of espeak's own buffers.
+pub fn list_voices() -> Vec<TtsVoice> {
+    let mut voices = Vec::new();
+    let mut list = unsafe { espeak_ListVoices(core::ptr::null_mut()) };
+    if list.is_null() {
+        log::warn!("espeak_ListVoices returned no list");
+        return voices;
+    }
+    unsafe {
+        while !(*list).is_null() {
+            let voice = &**list;
+            voices.push(TtsVoice {
+                name: string_from_c(voice.name),
+                languages: languages_from_c(voice.languages),
+                gender: TtsGender::from(voice.gender),
+                age: voice.age,
+                identifier: string_from_c(voice.identifier),
+            });
+            list = list.add(1);
+        }
+    }
+    voices
+}
+
+impl From<TtsGender> for c_uchar {
+    fn from(gender: TtsGender) -> Self {
+        match gender {
+            TtsGender::Unspecified => 0,
+            TtsGender::Male => 1,
+            TtsGender::Female => 2,
+        }
+    }
+}
+
+/// Makes `voice` the current voice of the engine, which must already be set up.
+pub fn set_voice(voice: &TtsVoiceSelect) -> Result<(), EspeakError> {
+    // an interior NUL can't name any voice
+    let to_cstring = |s: &str| std::ffi::CString::new(s).map_err(|_| EspeakError::VoiceNotFound);
+    match voice {
+        TtsVoiceSelect::Name(name) => {
+            let name = to_cstring(name)?;
+            EspeakError::check(unsafe { espeak_ng_SetVoiceByName(name.as_ptr() as *const c_char) })
+        }
+        TtsVoiceSelect::Properties { language, gender, age, variant } => {
+            let language = language.as_deref().map(to_cstring).transpose()?;
+            let mut selector = espeak_VOICE {
+                name: core::ptr::null(),
+                languages: language.as_ref().map_or(core::ptr::null(), |l| l.as_ptr() as *const c_char),
+                identifier: core::ptr::null(),
+                gender: (*gender).into(),
+                age: *age,
+                variant: *variant,
+                xx1: 0,
+                score: 0,
+                spare: core::ptr::null_mut(),
+            };
+            EspeakError::check(unsafe { espeak_ng_SetVoiceByProperties(&mut selector) })
+        }
+    }
+}